@@ -0,0 +1,254 @@
+//! Server-wide settings shared by every connection
+
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const USAGE: &str = "Usage: zombie_scape_server [--bind <ADDR>] [--port <PORT>] \
+    [--rate-limit <MSGS_PER_SEC>] [--auth-token <TOKEN>] [--save-dir <DIR>] \
+    [--health-port <PORT>] [--heartbeat-timeout <SECS>]";
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Interface to listen on
+    pub bind: IpAddr,
+    pub port: u16,
+    /// How often each connection is pinged
+    pub heartbeat_interval: Duration,
+    /// Connections with no pong for this long are dropped
+    pub heartbeat_timeout: Duration,
+    /// Sessions untouched for this long are evicted
+    pub idle_timeout: Duration,
+    /// How long a session outlives the connection that owns it, waiting for
+    /// a `Reattach`; zero releases it straight away
+    pub reattach_grace: Duration,
+    /// How often the registry is scanned for idle sessions
+    pub reap_interval: Duration,
+    /// Whether clients may enumerate every session with `ListSessions`
+    pub allow_session_listing: bool,
+    /// How long connections get to say goodbye on shutdown
+    pub shutdown_grace: Duration,
+    /// Client messages each connection may send per second, 0 for no limit
+    pub rate_limit: u32,
+    /// Most steps a single request may advance a session by
+    pub max_steps_per_request: u32,
+    /// Most sessions alive at once across the whole server
+    pub max_sessions: usize,
+    /// Most sessions a single connection may own at once
+    pub max_sessions_per_connection: usize,
+    /// Shared secret clients must send in `Authenticate`; `None` leaves the
+    /// server open
+    pub auth_token: Option<String>,
+    /// Where `SaveSession` writes sessions, and where they're reloaded from
+    /// on startup; `None` disables saving
+    pub save_dir: Option<PathBuf>,
+    /// Port for the plain HTTP `/health` and `/metrics` endpoints, on the
+    /// same interface as the WebSocket; `None` leaves them off
+    pub health_port: Option<u16>,
+    /// When a fugitive that stopped moving is reported stuck
+    pub stuck_detection: StuckDetection,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StuckDetection {
+    /// Steps the fugitive has to stay put for, 0 to never report it
+    pub steps: u32,
+    /// Furthest the fugitive may move over those steps and still count as
+    /// stuck, in cells
+    pub distance: f32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 8080,
+            heartbeat_interval: Duration::from_secs(10),
+            heartbeat_timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(600),
+            reattach_grace: Duration::from_secs(60),
+            reap_interval: Duration::from_secs(30),
+            allow_session_listing: false,
+            shutdown_grace: Duration::from_secs(5),
+            rate_limit: 100,
+            max_steps_per_request: 1000,
+            max_sessions: 1000,
+            max_sessions_per_connection: 10,
+            auth_token: None,
+            save_dir: None,
+            health_port: None,
+            stuck_detection: StuckDetection {
+                steps: 300,
+                distance: 0.1,
+            },
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Defaults, overridden by environment variables, overridden by `args`
+    pub fn load(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut config = ServerConfig::from_env();
+        config.apply_args(args)?;
+
+        if config.heartbeat_timeout <= config.heartbeat_interval {
+            return Err(format!(
+                "The heartbeat timeout must be longer than the {}s ping interval",
+                config.heartbeat_interval.as_secs()
+            ));
+        }
+
+        Ok(config)
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+
+    /// Defaults overridden by environment variables:
+    ///
+    /// - `ZOMBIE_BIND`: interface to listen on
+    /// - `ZOMBIE_PORT`: port to listen on
+    /// - `ZOMBIE_HEARTBEAT_TIMEOUT`: seconds without a pong before a
+    ///   connection is dropped
+    /// - `ZOMBIE_IDLE_TIMEOUT_SECS`: idle session timeout
+    /// - `ZOMBIE_REATTACH_GRACE_SECS`: how long sessions wait for a reattach
+    /// - `ZOMBIE_ALLOW_LIST_SESSIONS`: `true` to enable `ListSessions`
+    /// - `ZOMBIE_RATE_LIMIT`: client messages per second per connection
+    /// - `ZOMBIE_MAX_STEPS_PER_REQUEST`: cap on steps a single request can run
+    /// - `ZOMBIE_MAX_SESSIONS`: cap on sessions across the server
+    /// - `ZOMBIE_MAX_SESSIONS_PER_CONNECTION`: cap on sessions one connection owns
+    /// - `ZOMBIE_AUTH_TOKEN`: token clients must authenticate with
+    /// - `ZOMBIE_SAVE_DIR`: directory saved sessions are kept in
+    /// - `ZOMBIE_HEALTH_PORT`: port to serve `/health` and `/metrics` on
+    /// - `ZOMBIE_STUCK_STEPS`: steps without moving before a fugitive is stuck
+    /// - `ZOMBIE_STUCK_DISTANCE`: cells a stuck fugitive may still move
+    pub fn from_env() -> Self {
+        let mut config = ServerConfig::default();
+
+        if let Some(bind) = env_var("ZOMBIE_BIND") {
+            config.bind = bind;
+        }
+        if let Some(port) = env_var("ZOMBIE_PORT") {
+            config.port = port;
+        }
+        if let Some(secs) = env_var::<u64>("ZOMBIE_HEARTBEAT_TIMEOUT") {
+            config.heartbeat_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = env_var::<u64>("ZOMBIE_IDLE_TIMEOUT_SECS") {
+            config.idle_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = env_var::<u64>("ZOMBIE_REATTACH_GRACE_SECS") {
+            config.reattach_grace = Duration::from_secs(secs);
+        }
+
+        if let Some(allow) = env_var::<bool>("ZOMBIE_ALLOW_LIST_SESSIONS") {
+            config.allow_session_listing = allow;
+        }
+        if let Some(rate_limit) = env_var("ZOMBIE_RATE_LIMIT") {
+            config.rate_limit = rate_limit;
+        }
+        if let Some(max_steps) = env_var("ZOMBIE_MAX_STEPS_PER_REQUEST") {
+            config.max_steps_per_request = max_steps;
+        }
+        if let Some(max_sessions) = env_var("ZOMBIE_MAX_SESSIONS") {
+            config.max_sessions = max_sessions;
+        }
+        if let Some(max_sessions) = env_var("ZOMBIE_MAX_SESSIONS_PER_CONNECTION") {
+            config.max_sessions_per_connection = max_sessions;
+        }
+        if let Ok(token) = env::var("ZOMBIE_AUTH_TOKEN")
+            && !token.is_empty()
+        {
+            config.auth_token = Some(token);
+        }
+        if let Some(dir) = env_var("ZOMBIE_SAVE_DIR") {
+            config.save_dir = Some(dir);
+        }
+        if let Some(port) = env_var("ZOMBIE_HEALTH_PORT") {
+            config.health_port = Some(port);
+        }
+        if let Some(steps) = env_var("ZOMBIE_STUCK_STEPS") {
+            config.stuck_detection.steps = steps;
+        }
+        if let Some(distance) = env_var::<f32>("ZOMBIE_STUCK_DISTANCE")
+            && distance.is_finite()
+            && distance >= 0.0
+        {
+            config.stuck_detection.distance = distance;
+        }
+
+        config
+    }
+}
+
+impl ServerConfig {
+    /// Apply `--bind <ADDR>`, `--port <PORT>`, `--rate-limit <N>`,
+    /// `--auth-token <TOKEN>`, `--save-dir <DIR>`, `--health-port <PORT>` and
+    /// `--heartbeat-timeout <SECS>` command line flags
+    fn apply_args(&mut self, args: impl IntoIterator<Item = String>) -> Result<(), String> {
+        let mut args = args.into_iter();
+
+        while let Some(flag) = args.next() {
+            match flag.as_str() {
+                "--bind" => self.bind = flag_value(&flag, args.next())?,
+                "--port" => self.port = flag_value(&flag, args.next())?,
+                "--rate-limit" => self.rate_limit = flag_value(&flag, args.next())?,
+                "--auth-token" => self.auth_token = Some(flag_value(&flag, args.next())?),
+                "--save-dir" => self.save_dir = Some(flag_value(&flag, args.next())?),
+                "--health-port" => self.health_port = Some(flag_value(&flag, args.next())?),
+                "--heartbeat-timeout" => {
+                    self.heartbeat_timeout = Duration::from_secs(flag_value(&flag, args.next())?)
+                }
+                _ => return Err(format!("Unknown argument: {}", flag)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn flag_value<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {:?}", flag, value))
+}
+
+/// Read and parse an environment variable, warning when it's malformed
+fn env_var<T: FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok()?;
+
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            tracing::warn!(name, ?value, "Ignoring invalid environment variable");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(args: &[&str]) -> Result<ServerConfig, String> {
+        ServerConfig::load(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn heartbeat_timeout_flag_is_parsed() {
+        let config = load(&["--heartbeat-timeout", "45"]).unwrap();
+        assert_eq!(config.heartbeat_timeout, Duration::from_secs(45));
+        assert!(load(&["--heartbeat-timeout", "soon"]).is_err());
+    }
+
+    #[test]
+    fn heartbeat_timeout_must_outlast_the_ping_interval() {
+        let interval = ServerConfig::default().heartbeat_interval.as_secs().to_string();
+        assert!(load(&["--heartbeat-timeout", &interval]).is_err());
+        assert!(load(&["--heartbeat-timeout", "1"]).is_err());
+    }
+}
@@ -0,0 +1,244 @@
+//! Delta state updates
+//!
+//! Diffs a snapshot against the last one sent to a connection so only the
+//! changed agents and fields go over the wire.
+
+use crate::protocol::{AgentDelta, AgentSnapshot, GameStateSnapshot, ServerMessage, ZombieDelta};
+
+/// Build a `StateDelta` that turns `prev` into `next` when applied client-side
+pub fn state_delta(
+    session_id: String,
+    prev: &GameStateSnapshot,
+    next: &GameStateSnapshot,
+) -> ServerMessage {
+    let fugitive = diff_agent(Some(&prev.fugitive), &next.fugitive);
+
+    let zombies = next
+        .zombies
+        .iter()
+        .enumerate()
+        .filter_map(|(index, zombie)| {
+            // Match by id so removed zombies don't shift the comparison
+            let id = zombie.id()?;
+            let previous = prev.zombies.iter().find(|z| z.id() == Some(id));
+            diff_agent(previous, zombie).map(|changes| ZombieDelta { index, id, changes })
+        })
+        .collect();
+
+    let removed_ids = prev
+        .zombies
+        .iter()
+        .filter_map(AgentSnapshot::id)
+        .filter(|&id| !next.zombies.iter().any(|z| z.id() == Some(id)))
+        .collect();
+
+    ServerMessage::StateDelta {
+        session_id,
+        step: next.step,
+        status: next.status,
+        pursuing_count: next.pursuing_count,
+        closest_approach: next.closest_approach,
+        catch_imminent: next.catch_imminent,
+        reward: next.reward,
+        done: next.done,
+        end_reason: next.end_reason,
+        paused: next.paused,
+        events: next.events.clone(),
+        zombie_count: next.zombies.len(),
+        fugitive,
+        zombies,
+        removed_ids,
+    }
+}
+
+/// Diff a single agent; an agent with no previous snapshot is sent in full
+fn diff_agent(prev: Option<&AgentSnapshot>, next: &AgentSnapshot) -> Option<AgentDelta> {
+    let delta = AgentDelta {
+        position: changed(prev.map(|p| p.position()), next.position()),
+        velocity: changed(prev.map(|p| p.velocity()), next.velocity()),
+        state: changed(prev.map(|p| p.state()), next.state())
+            .flatten()
+            .map(str::to_string),
+        alertness: changed(prev.map(|p| p.alertness()), next.alertness()).flatten(),
+        vision_range: changed(prev.map(|p| p.vision_range()), next.vision_range()),
+        vision_angle: changed(prev.map(|p| p.vision_angle()), next.vision_angle()),
+        last_seen_position: changed(
+            prev.map(|p| p.last_seen_position()),
+            next.last_seen_position(),
+        ),
+        current_path: changed(prev.map(|p| p.current_path()), next.current_path())
+            .map(|path| path.cloned()),
+        target: changed(prev.map(|p| p.target()), next.target()).flatten(),
+        nearest_zombie_distance: changed(
+            prev.map(|p| p.nearest_zombie_distance()),
+            next.nearest_zombie_distance(),
+        ),
+        progress_to_exit: changed(prev.map(|p| p.progress_to_exit()), next.progress_to_exit())
+            .flatten(),
+        error: changed(prev.map(|p| p.error()), next.error())
+            .map(|error| error.map(str::to_string)),
+    };
+
+    let unchanged = delta.position.is_none()
+        && delta.velocity.is_none()
+        && delta.state.is_none()
+        && delta.alertness.is_none()
+        && delta.vision_range.is_none()
+        && delta.vision_angle.is_none()
+        && delta.last_seen_position.is_none()
+        && delta.current_path.is_none()
+        && delta.target.is_none()
+        && delta.nearest_zombie_distance.is_none()
+        && delta.progress_to_exit.is_none()
+        && delta.error.is_none();
+
+    (!unchanged).then_some(delta)
+}
+
+fn changed<T: PartialEq>(prev: Option<T>, next: T) -> Option<T> {
+    match prev {
+        Some(prev) if prev == next => None,
+        _ => Some(next),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::MazeInfo;
+    use zombie_scape::GameStatus;
+
+    fn zombie(id: u64, position: [f32; 2]) -> AgentSnapshot {
+        AgentSnapshot::Zombie {
+            id,
+            position,
+            velocity: [0.0, 0.0],
+            state: "wander".to_string(),
+            alertness: 0.0,
+            vision_range: 100.0,
+            vision_angle: 1.0,
+            last_seen_position: None,
+            current_path: None,
+            error: None,
+        }
+    }
+
+    fn fugitive(position: [f32; 2]) -> AgentSnapshot {
+        AgentSnapshot::Fugitive {
+            position,
+            velocity: [0.0, 0.0],
+            current_path: None,
+            target: None,
+            nearest_zombie_distance: Some(5.0),
+            progress_to_exit: 0.0,
+            vision_angle: 1.0,
+            vision_range: 100.0,
+            error: None,
+        }
+    }
+
+    fn snapshot(
+        step: u64,
+        fugitive: AgentSnapshot,
+        zombies: Vec<AgentSnapshot>,
+    ) -> GameStateSnapshot {
+        GameStateSnapshot {
+            step,
+            status: GameStatus::Running,
+            pursuing_count: 0,
+            closest_approach: None,
+            catch_imminent: false,
+            reward: None,
+            done: None,
+            end_reason: None,
+            paused: false,
+            dt: 0.016,
+            max_steps: None,
+            events: Vec::new(),
+            fugitive,
+            zombies,
+            maze_info: MazeInfo {
+                width: 3,
+                height: 3,
+                cell_size: 1.0,
+                start_position: [0.5, 0.5],
+                exit_position: [2.5, 2.5],
+                start_cell: [0, 0],
+                exit_cell: [2, 2],
+            },
+        }
+    }
+
+    #[test]
+    fn unchanged_agent_has_no_delta() {
+        let agent = zombie(1, [1.0, 1.0]);
+        assert!(diff_agent(Some(&agent), &agent).is_none());
+    }
+
+    #[test]
+    fn new_agent_is_sent_in_full() {
+        let delta = diff_agent(None, &zombie(1, [1.0, 1.0])).unwrap();
+
+        assert_eq!(delta.position, Some([1.0, 1.0]));
+        assert_eq!(delta.state.as_deref(), Some("wander"));
+        assert_eq!(delta.last_seen_position, Some(None));
+    }
+
+    #[test]
+    fn only_changed_fields_are_sent() {
+        let delta = diff_agent(Some(&fugitive([1.0, 1.0])), &fugitive([2.0, 1.0])).unwrap();
+
+        assert_eq!(delta.position, Some([2.0, 1.0]));
+        assert!(delta.velocity.is_none());
+        assert!(delta.nearest_zombie_distance.is_none());
+        assert!(delta.vision_range.is_none());
+    }
+
+    #[test]
+    fn zombies_are_matched_by_id_after_a_removal() {
+        let prev = snapshot(
+            1,
+            fugitive([0.5, 0.5]),
+            vec![zombie(1, [1.0, 1.0]), zombie(2, [2.0, 2.0])],
+        );
+        // Zombie 1 is gone, so zombie 2 moves to index 0 without moving
+        let next = snapshot(2, fugitive([0.5, 0.5]), vec![zombie(2, [2.0, 2.0])]);
+
+        let ServerMessage::StateDelta {
+            step,
+            zombie_count,
+            fugitive,
+            zombies,
+            removed_ids,
+            ..
+        } = state_delta("session".to_string(), &prev, &next)
+        else {
+            panic!("expected a state delta");
+        };
+        assert_eq!(step, 2);
+        assert_eq!(zombie_count, 1);
+        assert!(fugitive.is_none());
+        assert!(zombies.is_empty());
+        assert_eq!(removed_ids, vec![1]);
+    }
+
+    #[test]
+    fn moved_zombie_is_reported_with_id_and_index() {
+        let prev = snapshot(1, fugitive([0.5, 0.5]), vec![zombie(7, [1.0, 1.0])]);
+        let next = snapshot(2, fugitive([0.5, 0.5]), vec![zombie(7, [1.5, 1.0])]);
+
+        let delta = state_delta("session".to_string(), &prev, &next);
+        let ServerMessage::StateDelta {
+            zombies,
+            removed_ids,
+            ..
+        } = delta
+        else {
+            panic!("expected a state delta");
+        };
+        assert!(removed_ids.is_empty());
+        assert_eq!(zombies.len(), 1);
+        assert_eq!((zombies[0].index, zombies[0].id), (0, 7));
+        assert_eq!(zombies[0].changes.position, Some([1.5, 1.0]));
+    }
+}
@@ -0,0 +1,108 @@
+//! Plain HTTP endpoints for load balancers and orchestrators
+//!
+//! Only answers `GET /health` and `GET /metrics`, so rather than pull in an
+//! HTTP framework this reads the request line by hand and ignores everything
+//! else.
+
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+use tracing::{debug, error};
+
+use crate::metrics::Metrics;
+use crate::{SessionRegistry, lock_registry};
+use std::sync::Arc;
+
+/// Requests must arrive within this long, so idle sockets can't pile up
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+const JSON: &str = "application/json";
+const PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4";
+
+/// Longest request line or header accepted
+const MAX_LINE_LEN: u64 = 8 * 1024;
+
+pub async fn serve(
+    listener: TcpListener,
+    sessions: SessionRegistry,
+    metrics: Arc<Metrics>,
+    started: Instant,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let sessions = sessions.clone();
+                let metrics = metrics.clone();
+                tokio::spawn(async move {
+                    let response = respond(stream, &sessions, &metrics, started);
+                    let handled = tokio::time::timeout(REQUEST_TIMEOUT, response);
+                    if let Err(e) = handled.await {
+                        debug!(%peer, error = %e, "HTTP request timed out");
+                    }
+                });
+            }
+            Err(e) => error!(error = %e, "Failed to accept HTTP connection"),
+        }
+    }
+}
+
+async fn respond(
+    stream: TcpStream,
+    sessions: &SessionRegistry,
+    metrics: &Metrics,
+    started: Instant,
+) {
+    let mut stream = BufReader::new(stream);
+
+    let Some(request_line) = read_line(&mut stream).await else {
+        return;
+    };
+    // Drain the headers; nothing in them changes the answer
+    while let Some(header) = read_line(&mut stream).await {
+        if header.is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/health")) => {
+            let health = serde_json::json!({
+                "sessions": lock_registry(sessions).len(),
+                "uptime_s": started.elapsed().as_secs(),
+            });
+            ("200 OK", JSON, health.to_string())
+        }
+        (Some("GET"), Some("/metrics")) => {
+            let active_sessions = lock_registry(sessions).len();
+            ("200 OK", PROMETHEUS_TEXT, metrics.render(active_sessions))
+        }
+        (Some("GET"), _) => ("404 Not Found", JSON, r#"{"error":"not found"}"#.to_string()),
+        _ => ("405 Method Not Allowed", JSON, r#"{"error":"method not allowed"}"#.to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    if let Err(e) = stream.get_mut().write_all(response.as_bytes()).await {
+        debug!(error = %e, "Failed to write HTTP response");
+    }
+}
+
+/// Next line without its line ending, or `None` at end of stream, on a read
+/// error or when the line is too long
+async fn read_line(stream: &mut BufReader<TcpStream>) -> Option<String> {
+    let mut line = String::new();
+    let read = (&mut *stream).take(MAX_LINE_LEN).read_line(&mut line).await.ok()?;
+    if read == 0 || !line.ends_with('\n') {
+        return None;
+    }
+
+    Some(line.trim_end_matches(['\r', '\n']).to_string())
+}
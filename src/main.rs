@@ -1,286 +1,4121 @@
+mod config;
+mod delta;
+mod http;
+mod maze_stats;
+mod metrics;
+mod observation;
+mod pathfinding;
+mod persistence;
 mod protocol;
+mod rate_limit;
+mod seeds;
+mod send_queue;
+mod visibility;
+mod walls;
 
-use futures_util::{SinkExt, StreamExt};
-use protocol::{AgentSnapshot, ClientMessage, GameStateSnapshot, MazeInfo, ServerMessage};
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use config::{ServerConfig, StuckDetection};
+use futures_util::StreamExt;
+use protocol::compressed_frame;
+use protocol::{
+    AgentSnapshot, ClientMessage, Codec, CoordinateConvention, DecodeError, EndReason, Envelope,
+    ErrorCode, GameConfigSnapshot, GameEvent, GameStateSnapshot, MazeEncoding, MazeGrid, MazeInfo,
+    RewardShaping, ServerMessage, SessionSummary, StepMode, WallSegment, WireFormat,
+    ZombieDecision, ZombiePosition,
+};
+use metrics::Metrics;
+use persistence::{Despawn, SavedSession};
+use rate_limit::TokenBucket;
+use seeds::SeedSequence;
+use send_queue::{Delivery, SendQueue};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
 use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::{Callback, ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tracing::{Instrument, debug, error, info, info_span, warn};
+use tracing_subscriber::EnvFilter;
 use uuid::Uuid;
-use zombie_scape::{FugitiveSnapshot, GameConfig, GameState, ZombieSnapshot};
+use zombie_scape::{FugitiveSnapshot, GameConfig, GameState, GameStatus, ZombieSnapshot};
+
+/// Accepted range for `StartSimulation::tick_rate_hz`
+const MIN_TICK_RATE_HZ: u32 = 1;
+const MAX_TICK_RATE_HZ: u32 = 240;
+
+/// Most steps a `StepMode::WallClock` tick runs to catch up after falling
+/// behind; any older backlog is dropped
+const MAX_CATCH_UP_STEPS: u32 = 10;
+
+/// Accepted range for a session's simulation timestep, in seconds
+const MIN_DT: f32 = 0.001;
+const MAX_DT: f32 = 0.1;
+
+/// Largest maze side, in cells, a client may ask for
+const MAX_MAZE_SIZE: usize = 256;
+
+/// Most zombies a client may ask for
+const MAX_ZOMBIES: usize = 100;
+
+/// A catch counts as imminent once a zombie is within this many capture radii
+const CATCH_IMMINENT_FACTOR: f32 = 1.5;
+
+/// Most sessions a single `CreateSessions` request may ask for
+const MAX_BATCH_SESSIONS: u32 = 100;
+
+/// Simulated seconds a zombie's alertness takes to fade after it stops
+/// pursuing
+const ALERTNESS_DECAY_SECS: f32 = 5.0;
+
+/// Most sessions a single `SessionList` page may hold
+const MAX_SESSION_PAGE: usize = 100;
+
+/// Most snapshots a single `StepSimulationTrace` reply can carry
+const MAX_TRACE_FRAMES: u32 = 120;
+
+/// Most frames a recording keeps before it stops growing
+const MAX_RECORDING_FRAMES: usize = 10_000;
+
+/// Most steps a `VerifyReplay` rerun may take
+const MAX_VERIFY_STEPS: u64 = 100_000;
+
+/// Most steps a `Benchmark` may time
+const MAX_BENCHMARK_STEPS: u64 = 100_000;
+
+/// Furthest step a session can be saved at, since loading replays it
+const MAX_RESTORE_STEPS: u64 = 100_000;
+
+/// Most recent events kept for the next snapshot when many steps are batched
+const MAX_EVENTS_PER_SNAPSHOT: usize = 64;
+
+/// Snapshots buffered per spectator before it starts skipping ahead
+const SPECTATOR_BUFFER: usize = 16;
+
+/// Frames a connection queues for a slow client before it starts dropping
+/// pushed state updates
+const SEND_QUEUE_CAPACITY: usize = 64;
+
+/// Updates from tick loops and spectated sessions a connection buffers
+/// before newer ones are skipped, until it gets round to sending them
+const OUTBOUND_CAPACITY: usize = 64;
+
+/// How long a closing connection waits for its queued frames to be written
+const SEND_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Replies remembered per connection for answering repeated `request_id`s
+const REPLY_CACHE_SIZE: usize = 32;
+
+/// Identifies a connection, so it isn't sent back snapshots it caused
+type ConnectionId = u64;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
 
 /// Game session wrapper
 struct GameSession {
     id: String,
     state: GameState,
+    /// When set, neither `StepSimulation` nor the tick loop advance the game
+    paused: bool,
+    /// Encoding the client asked for when the session was created
+    maze_encoding: MazeEncoding,
+    /// Where reported positions put a cell, chosen at creation
+    coordinates: CoordinateConvention,
+    /// Last time a client message or tick touched this session
+    last_activity: Instant,
+    /// Fan-out of new snapshots to spectating connections, tagged with the
+    /// connection whose request produced them
+    spectators: broadcast::Sender<(ConnectionId, GameStateSnapshot)>,
+    /// Events from the latest `step` call, reported in snapshots
+    events: Vec<GameEvent>,
+    /// Simulated seconds requested via `AdvanceTime` but not yet stepped
+    time_accumulator: f32,
+    /// Seconds of simulated time per step
+    dt: f32,
+    /// Stable id of each zombie, parallel to `state.zombies`
+    zombie_ids: Vec<u64>,
+    /// Zombies removed this game, so forks and saves can remove them again
+    despawns: Vec<Despawn>,
+    /// Latest step each zombie was pursuing at, by id, for its alertness
+    last_pursuit_steps: HashMap<u64, u64>,
+    /// Length of the route from the start to the exit, for `progress_to_exit`
+    exit_distance: f32,
+    /// Smallest zombie-to-fugitive distance seen this game
+    closest_approach: Option<f32>,
+    /// Where the agents were before the latest step, for `GetInterpolated`
+    previous_positions: Option<AgentPositions>,
+    /// Set by the step that ended the game
+    end_reason: Option<EndReason>,
+    /// Step at which the game is ended as a timeout
+    max_steps: Option<u64>,
+    /// Frames captured between `StartRecording` and `StopRecording`
+    recording: Option<Recording>,
+    /// Pause as soon as a zombie switches to pursuit
+    break_on_detection: bool,
+    /// Hash of the token that lets a new connection `Reattach`
+    reconnect_token_hash: Option<[u8; 32]>,
+    /// How the tick loop advances the game
+    step_mode: StepMode,
+    /// Set once a client asks for rewards in snapshots
+    reward_shaping: Option<RewardShaping>,
+    /// Reward earned over the latest `step` call
+    reward: f32,
+    stuck_detection: StuckDetection,
+    /// Fugitive positions over the last `stuck_detection.steps` steps, oldest
+    /// first
+    fugitive_history: VecDeque<[f32; 2]>,
+    /// Whether the fugitive was reported stuck and hasn't moved since
+    stuck: bool,
+    metrics: Arc<Metrics>,
+}
+
+struct AgentPositions {
+    fugitive: [f32; 2],
+    zombies: Vec<ZombiePosition>,
+}
+
+struct Recording {
+    frames: Vec<GameStateSnapshot>,
+    /// Set once frames had to be dropped because the recording was full
+    truncated: bool,
 }
 
 impl GameSession {
-    fn new(config: GameConfig) -> Self {
+    fn new(
+        config: GameConfig,
+        maze_encoding: MazeEncoding,
+        dt: f32,
+        max_steps: Option<u64>,
+        stuck_detection: StuckDetection,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         let id = Uuid::new_v4().to_string();
         let state = GameState::new(config);
+        let zombie_ids = initial_zombie_ids(&state);
+        let exit_distance = exit_distance(&state);
+
+        let mut session = GameSession {
+            id,
+            state,
+            paused: false,
+            maze_encoding,
+            coordinates: CoordinateConvention::default(),
+            last_activity: Instant::now(),
+            spectators: broadcast::channel(SPECTATOR_BUFFER).0,
+            events: Vec::new(),
+            time_accumulator: 0.0,
+            dt,
+            zombie_ids,
+            despawns: Vec::new(),
+            last_pursuit_steps: HashMap::new(),
+            exit_distance,
+            closest_approach: None,
+            previous_positions: None,
+            end_reason: None,
+            max_steps,
+            recording: None,
+            break_on_detection: false,
+            reconnect_token_hash: None,
+            step_mode: StepMode::default(),
+            reward_shaping: None,
+            reward: 0.0,
+            stuck_detection,
+            fugitive_history: VecDeque::new(),
+            stuck: false,
+            metrics,
+        };
+        session.track_closest_approach();
+        session
+    }
+
+    /// Push a snapshot to every spectator of this session. `origin` already
+    /// has it as a direct reply, so its own subscription skips it.
+    fn publish(&self, origin: ConnectionId, state: &GameStateSnapshot) {
+        if self.spectators.receiver_count() > 0 {
+            let _ = self.spectators.send((origin, state.clone()));
+        }
+    }
 
-        GameSession { id, state }
+    /// Replay of this session's game so far, under a fresh id
+    fn fork(&self) -> SavedSession<GameConfig> {
+        SavedSession {
+            session_id: Uuid::new_v4().to_string(),
+            config: self.state.config.clone(),
+            dt: self.dt,
+            max_steps: self.max_steps,
+            maze_encoding: self.maze_encoding,
+            coordinates: self.coordinates,
+            step: self.state.current_step,
+            despawns: self.despawns.clone(),
+        }
+    }
+
+    /// Rebuild a saved session under the id it was saved with by replaying
+    /// it to the step it was saved at, removing zombies where it did. Slow
+    /// for long games, so callers cap `saved.step` first.
+    fn restore(
+        saved: SavedSession<GameConfig>,
+        stuck_detection: StuckDetection,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, String> {
+        let mut session = GameSession::new(
+            saved.config,
+            saved.maze_encoding,
+            saved.dt,
+            saved.max_steps,
+            stuck_detection,
+            metrics,
+        );
+        session.id = saved.session_id;
+        session.coordinates = saved.coordinates;
+
+        for despawn in saved.despawns {
+            session.replay_to(despawn.step)?;
+            if !session.despawn(despawn.zombie_index) {
+                return Err(format!(
+                    "No zombie {} to remove at step {}",
+                    despawn.zombie_index, despawn.step
+                ));
+            }
+        }
+        session.replay_to(saved.step)?;
+        session.events.clear();
+        Ok(session)
+    }
+
+    /// Step on to `step` while replaying a saved game
+    fn replay_to(&mut self, step: u64) -> Result<(), String> {
+        let steps = step.saturating_sub(self.state.current_step);
+        let steps = u32::try_from(steps)
+            .map_err(|_| format!("Cannot replay {} steps in one go", steps))?;
+        self.step(steps);
+        Ok(())
+    }
+
+    /// Remove a zombie and remember when, for replays. `false` if there's no
+    /// zombie at `zombie_index`.
+    fn despawn(&mut self, zombie_index: usize) -> bool {
+        if zombie_index >= self.state.zombies.len() {
+            return false;
+        }
+
+        self.state.zombies.remove(zombie_index);
+        let zombie_id = self.zombie_ids.remove(zombie_index);
+        self.last_pursuit_steps.remove(&zombie_id);
+        self.despawns.push(Despawn {
+            step: self.state.current_step,
+            zombie_index,
+        });
+        true
+    }
+
+    fn saved(&self) -> SavedSession<GameConfigSnapshot> {
+        SavedSession {
+            session_id: self.id.clone(),
+            config: GameConfigSnapshot::from(&self.state.config),
+            dt: self.dt,
+            max_steps: self.max_steps,
+            maze_encoding: self.maze_encoding,
+            coordinates: self.coordinates,
+            step: self.state.current_step,
+            despawns: self.despawns.clone(),
+        }
+    }
+
+    fn summary(&self) -> SessionSummary {
+        SessionSummary {
+            session_id: self.id.clone(),
+            current_step: self.state.current_step,
+            status: self.state.status,
+            zombie_count: self.state.zombies.len(),
+            paused: self.paused,
+        }
+    }
+
+    fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    /// Rebuild the game from its original config, keeping the session id.
+    ///
+    /// The maze is regenerated from `maze_seed`, so the layout is unchanged.
+    fn reset(&mut self) {
+        self.state = GameState::new(self.state.config.clone());
+        self.zombie_ids = initial_zombie_ids(&self.state);
+        self.despawns.clear();
+        self.last_pursuit_steps.clear();
+        self.paused = false;
+        self.events.clear();
+        self.time_accumulator = 0.0;
+        self.end_reason = None;
+        self.recording = None;
+        self.closest_approach = None;
+        self.track_closest_approach();
+        self.reward = 0.0;
+        self.previous_positions = None;
+        self.fugitive_history.clear();
+        self.stuck = false;
+    }
+
+    fn is_finished(&self) -> bool {
+        !matches!(self.state.status, GameStatus::Running) || self.end_reason.is_some()
     }
 
     fn step(&mut self, steps: u32) {
-        const DT: f32 = 0.016; // ~60 FPS timestep
+        self.touch();
+        self.events.clear();
+        self.reward = 0.0;
+        let started = Instant::now();
+        let start_step = self.state.current_step;
+
+        let mut zombie_states = self.zombie_states();
         for _ in 0..steps {
-            self.state.step(DT);
+            if self.end_reason.is_some() {
+                break;
+            }
+            // Only worked out when a reward needs it
+            let progress_before = match self.reward_shaping {
+                Some(_) => self.fugitive_progress(),
+                None => 0.0,
+            };
+            self.previous_positions = Some(self.agent_positions());
+            self.state.step(self.dt);
+
+            let next_states = self.zombie_states();
+            let mut step_events = Vec::new();
+            for (zombie_index, (from, to)) in zombie_states.iter().zip(&next_states).enumerate() {
+                if from != to {
+                    step_events.push(GameEvent::ZombieStateChanged {
+                        zombie_index,
+                        zombie_id: self.zombie_ids[zombie_index],
+                        from: from.clone(),
+                        to: to.clone(),
+                        step: self.state.current_step,
+                    });
+                }
+            }
+            for (&zombie_id, state) in self.zombie_ids.iter().zip(&next_states) {
+                if is_pursuit(state) {
+                    self.last_pursuit_steps.insert(zombie_id, self.state.current_step);
+                }
+            }
+            if let Some(shaping) = &self.reward_shaping {
+                self.reward += self.step_reward(shaping, progress_before, &next_states);
+            }
+            zombie_states = next_states;
+            self.track_closest_approach();
+            step_events.extend(self.check_stuck());
+
+            if self.end_reason.is_none() {
+                self.end_reason = self.detect_end_reason();
+            }
+
+            let detected = self.break_on_detection && step_events.iter().any(is_detection);
+
+            self.record_frame(&step_events);
+            for event in step_events {
+                self.record_event(event);
+            }
+
+            if detected {
+                info!(session_id = %self.id, step = self.state.current_step, "Paused on detection");
+                self.paused = true;
+                break;
+            }
         }
+
+        self.metrics.record_steps(self.state.current_step - start_step, started.elapsed());
     }
 
-    fn get_snapshot(&self) -> GameStateSnapshot {
-        // Convert fugitive to AgentSnapshot::Fugitive
-        let fugitive_snapshot =
-            FugitiveSnapshot::from_agent(&self.state.fugitive, &self.state.graph);
-        let fugitive = AgentSnapshot::Fugitive {
-            position: fugitive_snapshot.position,
-            velocity: fugitive_snapshot.velocity,
-            current_path: fugitive_snapshot.current_path, // TODO: Add fugitive path if needed for visualization
-            vision_range: fugitive_snapshot.vision_range,
-            vision_angle: fugitive_snapshot.vision_angle,
+    /// Remember where the fugitive is, and report it stuck the first step it
+    /// has stayed within `stuck_detection.distance` for
+    /// `stuck_detection.steps` steps
+    fn check_stuck(&mut self) -> Option<GameEvent> {
+        let window = self.stuck_detection.steps as usize;
+        if window == 0 || !matches!(self.state.status, GameStatus::Running) {
+            return None;
+        }
+
+        let fugitive = FugitiveSnapshot::from_agent(&self.state.fugitive, &self.state.graph);
+        let [fx, fy] = fugitive.position;
+        // One more position than steps, to cover the moves between them
+        if self.fugitive_history.len() > window {
+            self.fugitive_history.pop_front();
+        }
+        self.fugitive_history.push_back([fx, fy]);
+
+        let limit = self.stuck_detection.distance * self.state.config.cell_size;
+        let stayed = self.fugitive_history.len() > window
+            && self.fugitive_history.iter().all(|[x, y]| (x - fx).hypot(y - fy) <= limit);
+        let newly_stuck = stayed && !self.stuck;
+        self.stuck = stayed;
+
+        newly_stuck.then(|| {
+            warn!(session_id = %self.id, step = self.state.current_step, "Fugitive is stuck");
+            GameEvent::FugitiveStuck {
+                position: self.report([fx, fy]),
+                steps: self.stuck_detection.steps,
+                step: self.state.current_step,
+            }
+        })
+    }
+
+    /// Append the current state to the recording, if one is running, with
+    /// only the events of the latest step
+    fn record_frame(&mut self, events: &[GameEvent]) {
+        let Some(recording) = &self.recording else {
+            return;
+        };
+        if recording.frames.len() >= MAX_RECORDING_FRAMES {
+            if let Some(recording) = &mut self.recording {
+                recording.truncated = true;
+            }
+            return;
+        }
+
+        let mut frame = self.get_snapshot();
+        frame.events = events.to_vec();
+        if let Some(recording) = &mut self.recording {
+            recording.frames.push(frame);
+        }
+    }
+
+    fn detect_end_reason(&self) -> Option<EndReason> {
+        match self.state.status {
+            GameStatus::Running => self
+                .max_steps
+                .filter(|&max_steps| self.state.current_step >= max_steps)
+                .map(|_| EndReason::Timeout),
+            GameStatus::FugitiveWon => Some(EndReason::Escaped),
+            GameStatus::ZombiesCaptured => Some(EndReason::Caught {
+                zombie_id: self.closest_zombie_id(),
+            }),
+        }
+    }
+
+    /// The maze as text: `#` for walls, `.` for open cells, `S` and `E` for
+    /// the start and exit, then `Z` for zombies and `F` for the fugitive on
+    /// top
+    fn maze_ascii(&self) -> String {
+        let grid = &self.state.grid;
+        let mut rows: Vec<Vec<char>> = (0..grid.height())
+            .map(|y| {
+                (0..grid.width())
+                    .map(|x| if is_cell_walkable(grid, x, y) { '.' } else { '#' })
+                    .collect()
+            })
+            .collect();
+
+        let positions = self.agent_positions();
+        let start = self.state.start_position();
+        let exit = self.state.exit_position();
+        let marks = [([start.x(), start.y()], 'S'), ([exit.x(), exit.y()], 'E')]
+            .into_iter()
+            .chain(positions.zombies.iter().map(|zombie| (zombie.position, 'Z')))
+            .chain([(positions.fugitive, 'F')]);
+        for (position, mark) in marks {
+            if let Some((x, y)) = pathfinding::cell_at(grid, position) {
+                rows[y][x] = mark;
+            }
+        }
+
+        rows.into_iter()
+            .map(|row| row.into_iter().collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// 1.0 at the latest step the zombie was pursuing, fading linearly to 0.0
+    /// over `ALERTNESS_DECAY_SECS` of simulated time
+    fn alertness(&self, zombie_id: u64) -> f32 {
+        match self.last_pursuit_steps.get(&zombie_id) {
+            Some(&step) => {
+                let since = (self.state.current_step - step) as f32 * self.dt;
+                (1.0 - since / ALERTNESS_DECAY_SECS).max(0.0)
+            }
+            None => 0.0,
+        }
+    }
+
+    /// Engine `position` in the session's coordinates
+    fn report(&self, position: [f32; 2]) -> [f32; 2] {
+        self.coordinates.report(position, self.state.config.cell_size)
+    }
+
+    fn report_path(&self, path: Vec<[f32; 2]>) -> Vec<[f32; 2]> {
+        path.into_iter().map(|position| self.report(position)).collect()
+    }
+
+    /// Client `position` in the session's coordinates, in the engine's
+    fn world(&self, position: [f32; 2]) -> [f32; 2] {
+        self.coordinates.world(position, self.state.config.cell_size)
+    }
+
+    fn maze_grid(&self) -> MazeGrid {
+        serialize_grid(&self.state.grid, self.maze_encoding, self.coordinates)
+    }
+
+    fn agent_positions(&self) -> AgentPositions {
+        let fugitive = FugitiveSnapshot::from_agent(&self.state.fugitive, &self.state.graph);
+        let zombies = self
+            .state
+            .zombies
+            .iter()
+            .zip(&self.zombie_ids)
+            .map(|(z, &id)| ZombiePosition {
+                id,
+                position: ZombieSnapshot::from_agent(z, &self.state.graph).position,
+            })
+            .collect();
+
+        AgentPositions {
+            fugitive: fugitive.position,
+            zombies,
+        }
+    }
+
+    /// Positions `alpha` of the way from before the latest step to now. Agents
+    /// with nothing to start from, before the first step or after a reset,
+    /// stay where they are.
+    fn interpolated_positions(&self, alpha: f32) -> AgentPositions {
+        let lerp = |from: [f32; 2], to: [f32; 2]| {
+            [from[0] + (to[0] - from[0]) * alpha, from[1] + (to[1] - from[1]) * alpha]
         };
 
+        let mut current = self.agent_positions();
+        let Some(previous) = &self.previous_positions else {
+            return current;
+        };
+
+        current.fugitive = lerp(previous.fugitive, current.fugitive);
+        for zombie in &mut current.zombies {
+            if let Some(before) = previous.zombies.iter().find(|z| z.id == zombie.id) {
+                zombie.position = lerp(before.position, zombie.position);
+            }
+        }
+
+        current
+    }
+
+    /// Fold the current distance between the fugitive and its nearest zombie
+    /// into `closest_approach`
+    fn track_closest_approach(&mut self) {
+        let fugitive = FugitiveSnapshot::from_agent(&self.state.fugitive, &self.state.graph);
+        let [fx, fy] = fugitive.position;
+        let nearest = self
+            .state
+            .zombies
+            .iter()
+            .map(|z| {
+                let [zx, zy] = ZombieSnapshot::from_agent(z, &self.state.graph).position;
+                (zx - fx).hypot(zy - fy)
+            })
+            .min_by(f32::total_cmp);
+
+        // A diverged agent would otherwise stick a NaN in for good
+        if let Some(nearest) = nearest.filter(|nearest| nearest.is_finite()) {
+            let closest = self.closest_approach.map_or(nearest, |closest| closest.min(nearest));
+            self.closest_approach = Some(closest);
+        }
+    }
+
+    /// Id of the zombie nearest the fugitive, taken as the one that caught it
+    fn closest_zombie_id(&self) -> Option<u64> {
+        let fugitive = FugitiveSnapshot::from_agent(&self.state.fugitive, &self.state.graph);
+        let [fx, fy] = fugitive.position;
+
+        self.state
+            .zombies
+            .iter()
+            .zip(&self.zombie_ids)
+            .map(|(z, &id)| {
+                let [zx, zy] = ZombieSnapshot::from_agent(z, &self.state.graph).position;
+                (id, (zx - fx).powi(2) + (zy - fy).powi(2))
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(id, _)| id)
+    }
+
+    /// Advance by an amount of simulated time, running as many whole `dt`
+    /// steps as fit and carrying the remainder over to the next call.
+    fn advance_time(&mut self, seconds: f32) {
+        self.time_accumulator += seconds;
+        let steps = (self.time_accumulator / self.dt).floor() as u32;
+        self.time_accumulator -= steps as f32 * self.dt;
+        self.step(steps);
+    }
+
+    /// Current FSM state of every zombie
+    fn zombie_states(&self) -> Vec<String> {
+        self.state
+            .zombies
+            .iter()
+            .map(|z| ZombieSnapshot::from_agent(z, &self.state.graph).state)
+            .collect()
+    }
+
+    /// Keep an event for the next snapshot, dropping the oldest past the cap
+    fn record_event(&mut self, event: GameEvent) {
+        if self.events.len() == MAX_EVENTS_PER_SNAPSHOT {
+            self.events.remove(0);
+        }
+        self.events.push(event);
+    }
+
+    fn get_snapshot(&self) -> GameStateSnapshot {
         // Convert zombies to AgentSnapshot::Zombie with debug data
         let zombie_snapshots: Vec<AgentSnapshot> = self
             .state
             .zombies
             .iter()
-            .map(|z| {
-                let zs = ZombieSnapshot::from_agent(z, &self.state.graph);
-                AgentSnapshot::Zombie {
-                    position: zs.position,
+            .zip(&self.zombie_ids)
+            .map(|(z, &id)| {
+                let mut zs = ZombieSnapshot::from_agent(z, &self.state.graph);
+                let problems = sanitize_motion(&mut zs.position, &mut zs.velocity);
+                let mut zombie = AgentSnapshot::Zombie {
+                    id,
+                    position: self.report(zs.position),
                     velocity: zs.velocity,
+                    alertness: self.alertness(id),
                     state: zs.state,
                     vision_range: zs.vision_range,
                     vision_angle: zs.vision_angle,
-                    last_seen_position: zs.last_seen_position,
-                    current_path: zs.current_path,
-                }
+                    last_seen_position: zs.last_seen_position.map(|p| self.report(p)),
+                    current_path: zs.current_path.map(|path| self.report_path(path)),
+                    error: None,
+                };
+                sanitize_agent(&mut zombie, problems);
+                zombie
             })
             .collect();
 
-        let start_pos = self.state.start_position();
+        // Convert fugitive to AgentSnapshot::Fugitive
+        let mut fugitive_snapshot =
+            FugitiveSnapshot::from_agent(&self.state.fugitive, &self.state.graph);
+        let fugitive_problems =
+            sanitize_motion(&mut fugitive_snapshot.position, &mut fugitive_snapshot.velocity);
+        let progress_to_exit = self.progress_to_exit(&fugitive_snapshot);
+        fugitive_snapshot.position = self.report(fugitive_snapshot.position);
+        fugitive_snapshot.current_path =
+            fugitive_snapshot.current_path.map(|path| self.report_path(path));
         let exit_pos = self.state.exit_position();
+        let target = fugitive_snapshot
+            .current_path
+            .as_ref()
+            .and_then(|path| path.last().copied())
+            .unwrap_or_else(|| self.report([exit_pos.x(), exit_pos.y()]));
+        let [fx, fy] = fugitive_snapshot.position;
+        let nearest_zombie_distance = zombie_snapshots
+            .iter()
+            .map(|zombie| {
+                let [zx, zy] = zombie.position();
+                (zx - fx).hypot(zy - fy)
+            })
+            .min_by(f32::total_cmp);
+        let mut fugitive = AgentSnapshot::Fugitive {
+            position: fugitive_snapshot.position,
+            velocity: fugitive_snapshot.velocity,
+            current_path: fugitive_snapshot.current_path, // TODO: Add fugitive path if needed for visualization
+            target: Some(target),
+            nearest_zombie_distance,
+            progress_to_exit,
+            vision_range: fugitive_snapshot.vision_range,
+            vision_angle: fugitive_snapshot.vision_angle,
+            error: None,
+        };
+        sanitize_agent(&mut fugitive, fugitive_problems);
+
+        let pursuing_count = zombie_snapshots
+            .iter()
+            .filter(|zombie| zombie.state().is_some_and(is_pursuit))
+            .count();
+        let catch_radius = CATCH_IMMINENT_FACTOR * self.state.config.capture_radius;
+        let catch_imminent = fugitive.nearest_zombie_distance().is_some_and(|d| d < catch_radius);
 
         GameStateSnapshot {
             step: self.state.current_step,
             status: self.state.status,
+            pursuing_count,
+            closest_approach: self.closest_approach,
+            catch_imminent,
+            reward: self.reward_shaping.as_ref().map(|_| finite_or_zero(self.reward)),
+            done: self.reward_shaping.as_ref().map(|_| self.is_finished()),
+            end_reason: self.end_reason,
+            paused: self.paused,
+            dt: self.dt,
+            max_steps: self.max_steps,
+            events: self.events.clone(),
             fugitive,
             zombies: zombie_snapshots,
-            maze_info: MazeInfo {
-                width: self.state.config.maze_width,
-                height: self.state.config.maze_height,
-                cell_size: self.state.config.cell_size,
-                start_position: [start_pos.x(), start_pos.y()],
-                exit_position: [exit_pos.x(), exit_pos.y()],
-            },
+            maze_info: self.maze_info(),
         }
     }
-}
 
-/// Session registry (for Milestone 3, currently single session)
-type SessionRegistry = Arc<Mutex<HashMap<String, Arc<Mutex<GameSession>>>>>;
+    /// How much of the route from the start the fugitive has covered,
+    /// judged by what's left of its path, or the straight line to the exit
+    /// while it has none
+    fn progress_to_exit(&self, fugitive: &FugitiveSnapshot) -> f32 {
+        let [fx, fy] = fugitive.position;
+        let exit_pos = self.state.exit_position();
+        let [ex, ey] = [exit_pos.x(), exit_pos.y()];
+        let remaining = match fugitive.current_path.as_deref() {
+            Some(path @ [[px, py], ..]) => {
+                (px - fx).hypot(py - fy) + pathfinding::path_length(path)
+            }
+            _ => (ex - fx).hypot(ey - fy),
+        };
 
-#[tokio::main]
-async fn main() {
-    let addr = "127.0.0.1:8080";
-    let listener = TcpListener::bind(&addr).await.expect("Failed to bind");
+        if self.exit_distance > 0.0 {
+            (1.0 - remaining / self.exit_distance).clamp(0.0, 1.0)
+        } else {
+            1.0
+        }
+    }
 
-    println!("🎮 Zombie Escape Server listening on {}", addr);
-    println!("📝 Milestone 1: Single fugitive navigation");
-    println!();
+    /// The fugitive's current `progress_to_exit`
+    fn fugitive_progress(&self) -> f32 {
+        let fugitive = FugitiveSnapshot::from_agent(&self.state.fugitive, &self.state.graph);
+        self.progress_to_exit(&fugitive)
+    }
 
-    let sessions: SessionRegistry = Arc::new(Mutex::new(HashMap::new()));
+    /// Reward for the step just taken, given the fugitive's progress before
+    /// it and the zombies' states after it
+    fn step_reward(&self, shaping: &RewardShaping, progress_before: f32, states: &[String]) -> f32 {
+        let progress = self.fugitive_progress() - progress_before;
+        let pursuing = states.iter().filter(|state| is_pursuit(state)).count();
 
-    while let Ok((stream, peer)) = listener.accept().await {
-        println!("🔌 New connection from {}", peer);
-        let sessions = Arc::clone(&sessions);
-        tokio::spawn(handle_connection(stream, sessions, peer.to_string()));
+        let terminal = match self.state.status {
+            GameStatus::FugitiveWon => shaping.escape_bonus,
+            GameStatus::ZombiesCaptured => -shaping.capture_penalty,
+            GameStatus::Running => 0.0,
+        };
+
+        shaping.progress_weight * progress - shaping.seen_penalty * pursuing as f32 + terminal
     }
-}
 
-async fn handle_connection(stream: TcpStream, sessions: SessionRegistry, peer: String) {
-    let ws_stream = match tokio_tungstenite::accept_async(stream).await {
-        Ok(ws) => ws,
-        Err(e) => {
-            eprintln!("❌ WebSocket handshake error with {}: {}", peer, e);
-            return;
+    fn maze_info(&self) -> MazeInfo {
+        let start_pos = self.state.start_position();
+        let exit_pos = self.state.exit_position();
+        let start_position = [start_pos.x(), start_pos.y()];
+        let exit_position = [exit_pos.x(), exit_pos.y()];
+        // Both are cell centres, so always inside the grid
+        let cell = |position| {
+            let (x, y) = pathfinding::cell_at(&self.state.grid, position).unwrap_or_default();
+            [x, y]
+        };
+
+        MazeInfo {
+            width: self.state.config.maze_width,
+            height: self.state.config.maze_height,
+            cell_size: self.state.config.cell_size,
+            start_position: self.report(start_position),
+            exit_position: self.report(exit_position),
+            start_cell: cell(start_position),
+            exit_cell: cell(exit_position),
         }
-    };
+    }
+}
 
-    println!("✅ WebSocket connection established with {}", peer);
+/// Reject configs the engine would panic on or turn into a meaningless game
+fn validate_game_config(config: &GameConfig) -> Result<(), String> {
+    for (name, size) in [("maze_width", config.maze_width), ("maze_height", config.maze_height)] {
+        if !(1..=MAX_MAZE_SIZE).contains(&size) {
+            return Err(format!(
+                "{} must be between 1 and {}, got {}",
+                name, MAX_MAZE_SIZE, size
+            ));
+        }
+    }
 
-    let (mut write, mut read) = ws_stream.split();
+    if config.zombie_count > MAX_ZOMBIES {
+        return Err(format!(
+            "zombie_count must be at most {}, got {}",
+            MAX_ZOMBIES, config.zombie_count
+        ));
+    }
 
-    while let Some(msg) = read.next().await {
-        match msg {
-            Ok(Message::Text(text)) => {
-                println!("📨 Received from {}: {}", peer, text);
+    if !config.cell_size.is_finite() || config.cell_size <= 0.0 {
+        return Err(format!("cell_size must be positive, got {}", config.cell_size));
+    }
 
-                // Parse client message
-                let response = match serde_json::from_str::<ClientMessage>(&text) {
-                    Ok(client_msg) => handle_client_message(client_msg, &sessions).await,
-                    Err(e) => ServerMessage::Error {
-                        message: format!("Invalid JSON: {}", e),
-                        code: "parse_error".to_string(),
-                    },
-                };
+    for (name, value) in [
+        ("fugitive_speed", config.fugitive_speed),
+        ("zombie_speed", config.zombie_speed),
+    ] {
+        if !value.is_finite() || value < 0.0 {
+            return Err(format!("{} must be zero or more, got {}", name, value));
+        }
+    }
 
-                // Send response
-                let response_json = serde_json::to_string(&response).unwrap();
-                println!("📤 Sending to {}: {}", peer, response_json);
+    if !config.capture_radius.is_finite() || config.capture_radius <= 0.0 {
+        return Err(format!("capture_radius must be positive, got {}", config.capture_radius));
+    }
 
-                if let Err(e) = write.send(Message::Text(response_json.into())).await {
-                    eprintln!("❌ Failed to send message to {}: {}", peer, e);
-                    break;
-                }
-            }
-            Ok(Message::Close(_)) => {
-                println!("👋 Client {} disconnected", peer);
-                break;
-            }
-            Ok(_) => {}
-            Err(e) => {
-                eprintln!("❌ WebSocket error with {}: {}", peer, e);
-                break;
-            }
-        }
+    if !(0.0..=1.0).contains(&config.braid_probability) {
+        return Err(format!(
+            "braid_probability must be between 0 and 1, got {}",
+            config.braid_probability
+        ));
     }
 
-    println!("🔌 Connection closed with {}", peer);
+    Ok(())
 }
 
-fn serialize_grid(grid: &zombie_scape::Grid2D) -> Vec<Vec<String>> {
-    let height = grid.height();
-    let width = grid.width();
-    let cell_size = grid.cell_size();
-    let mut result = Vec::new();
-
-    for y in 0..height {
-        let mut row = Vec::new();
-        for x in 0..width {
-            // Convert grid coordinates to world coordinates (center of cell)
-            let world_x = (x as f32 + 0.5) * cell_size;
-            let world_y = (y as f32 + 0.5) * cell_size;
-            let pos = zombie_scape::Vector2D::from_coords(world_x, world_y);
-
-            let cell_type = if grid.is_walkable(pos) {
-                "walkable"
-            } else {
-                "wall"
-            };
-            row.push(cell_type.to_string());
-        }
-        result.push(row);
+/// Rerun a game from its config to `final_step`, or until it ends, and
+/// return the step reached and where the fugitive is
+fn rerun_game(config: GameConfig, dt: f32, final_step: u64) -> (u64, [f32; 2]) {
+    let mut state = GameState::new(config);
+    while state.current_step < final_step && matches!(state.status, GameStatus::Running) {
+        state.step(dt);
     }
 
-    result
+    let fugitive = FugitiveSnapshot::from_agent(&state.fugitive, &state.graph);
+    (state.current_step, fugitive.position)
 }
 
-async fn handle_client_message(msg: ClientMessage, sessions: &SessionRegistry) -> ServerMessage {
-    match msg {
-        ClientMessage::CreateSession { config } => {
-            println!("🎮 Creating new session with config: {:?}", config);
-
-            let session = GameSession::new(config);
-            let session_id = session.id.clone();
-            let initial_state = session.get_snapshot();
+/// Step a fresh game `steps` times, or until it ends, returning the steps
+/// run, the time they took and the final status
+fn benchmark_game(config: GameConfig, dt: f32, steps: u64) -> (u64, Duration, GameStatus) {
+    let mut state = GameState::new(config);
 
-            // Serialize the maze grid
-            let maze_grid = serialize_grid(&session.state.grid);
+    let started = Instant::now();
+    while state.current_step < steps && matches!(state.status, GameStatus::Running) {
+        state.step(dt);
+    }
 
-            // Store session
-            let session_arc = Arc::new(Mutex::new(session));
-            sessions
-                .lock()
-                .unwrap()
-                .insert(session_id.clone(), session_arc);
+    (state.current_step, started.elapsed(), state.status)
+}
 
-            println!("✅ Session created: {}", session_id);
+/// Zero any coordinate of an agent's position or velocity from the engine
+/// that isn't a finite number, before anything is worked out from them, and
+/// describe what was wrong
+fn sanitize_motion(position: &mut [f32; 2], velocity: &mut [f32; 2]) -> Vec<String> {
+    let mut problems = Vec::new();
+    sanitize_floats("position", position, &mut problems);
+    sanitize_floats("velocity", velocity, &mut problems);
+    problems
+}
 
-            ServerMessage::SessionCreated {
-                session_id,
-                initial_state,
-                maze_grid,
+/// Zero every float in an agent's snapshot that isn't a finite number, which
+/// JSON can't carry, and set its `error` to what was wrong, including the
+/// `problems` already found by `sanitize_motion`
+fn sanitize_agent(agent: &mut AgentSnapshot, mut problems: Vec<String>) {
+    let mut check = |name: &str, values: &mut [f32]| sanitize_floats(name, values, &mut problems);
+    let error = match agent {
+        AgentSnapshot::Fugitive {
+            position,
+            velocity,
+            current_path,
+            target,
+            nearest_zombie_distance,
+            progress_to_exit,
+            vision_angle,
+            vision_range,
+            error,
+        } => {
+            check("position", position);
+            check("velocity", velocity);
+            current_path.iter_mut().flatten().for_each(|point| check("current_path point", point));
+            target.iter_mut().for_each(|target| check("target", target));
+            if let Some(distance) = nearest_zombie_distance {
+                check("nearest_zombie_distance", slice::from_mut(distance));
             }
+            check("progress_to_exit", slice::from_mut(progress_to_exit));
+            check("vision_angle", slice::from_mut(vision_angle));
+            check("vision_range", slice::from_mut(vision_range));
+            error
         }
+        AgentSnapshot::Zombie {
+            position,
+            velocity,
+            alertness,
+            vision_range,
+            vision_angle,
+            last_seen_position,
+            current_path,
+            error,
+            ..
+        } => {
+            check("position", position);
+            check("velocity", velocity);
+            check("alertness", slice::from_mut(alertness));
+            check("vision_range", slice::from_mut(vision_range));
+            check("vision_angle", slice::from_mut(vision_angle));
+            last_seen_position.iter_mut().for_each(|seen| check("last_seen_position", seen));
+            current_path.iter_mut().flatten().for_each(|point| check("current_path point", point));
+            error
+        }
+    };
 
-        ClientMessage::StepSimulation { session_id, steps } => {
-            println!("▶️  Stepping session {} by {} steps", session_id, steps);
+    *error = (!problems.is_empty()).then(|| problems.join(", "));
+}
 
-            let sessions = sessions.lock().unwrap();
+/// Zero those of `values` that aren't finite numbers and describe them in
+/// `problems`
+fn sanitize_floats(name: &str, values: &mut [f32], problems: &mut Vec<String>) {
+    if values.iter().all(|value| value.is_finite()) {
+        return;
+    }
 
-            match sessions.get(&session_id) {
-                Some(session_arc) => {
-                    let mut session = session_arc.lock().unwrap();
-                    session.step(steps);
-                    let state = session.get_snapshot();
+    match values {
+        [value] => problems.push(format!("non-finite {} {}", name, value)),
+        _ => problems.push(format!("non-finite {} {:?}", name, values)),
+    }
+    values.iter_mut().filter(|value| !value.is_finite()).for_each(|value| *value = 0.0);
+}
 
-                    println!("✅ Step {}: Status = {:?}", state.step, state.status);
+/// `value`, or 0.0 in place of the NaNs and infinities JSON can't carry
+fn finite_or_zero(value: f32) -> f32 {
+    if value.is_finite() { value } else { 0.0 }
+}
 
-                    ServerMessage::StateUpdate { session_id, state }
-                }
-                None => ServerMessage::Error {
-                    message: format!("Session not found: {}", session_id),
-                    code: "session_not_found".to_string(),
-                },
-            }
-        }
+/// Whether an event is a zombie starting to chase the fugitive
+fn is_detection(event: &GameEvent) -> bool {
+    match event {
+        GameEvent::ZombieStateChanged { to, .. } => is_pursuit(to),
+        GameEvent::FugitiveStuck { .. } => false,
+    }
+}
 
-        ClientMessage::GetState { session_id } => {
-            let sessions = sessions.lock().unwrap();
+/// Whether a zombie FSM state is the one for chasing the fugitive
+fn is_pursuit(state: &str) -> bool {
+    state.eq_ignore_ascii_case("pursuit")
+}
 
-            match sessions.get(&session_id) {
-                Some(session_arc) => {
-                    let session = session_arc.lock().unwrap();
-                    let state = session.get_snapshot();
+/// Length of the shortest route from the fugitive's start to the exit, or
+/// the straight line between them if walls are in the way
+fn exit_distance(state: &GameState) -> f32 {
+    let (start, exit) = (state.start_position(), state.exit_position());
+    let (from, to) = ([start.x(), start.y()], [exit.x(), exit.y()]);
 
-                    ServerMessage::StateUpdate { session_id, state }
+    pathfinding::shortest_path(&state.grid, from, to)
+        .map(|path| pathfinding::path_length(&path))
+        .unwrap_or_else(|| (to[0] - from[0]).hypot(to[1] - from[1]))
+}
+
+/// Ids for the zombies a fresh `GameState` starts with. Id 0 is reserved for
+/// the fugitive.
+fn initial_zombie_ids(state: &GameState) -> Vec<u64> {
+    (1..=state.zombies.len() as u64).collect()
+}
+
+/// Registry entry: the session plus its auto-step task, if one is running
+struct SessionEntry {
+    session: Arc<Mutex<GameSession>>,
+    tick_task: Option<JoinHandle<()>>,
+    /// Connection the session is released with. Sessions restored from disk
+    /// and ones waiting for a reattach have none.
+    owner: Option<ConnectionId>,
+    /// When the owner disconnected, if the session is waiting for a reattach
+    detached_at: Option<Instant>,
+}
+
+impl SessionEntry {
+    fn stop_ticking(&mut self) {
+        if let Some(task) = self.tick_task.take() {
+            task.abort();
+        }
+    }
+}
+
+/// Session registry (for Milestone 3, currently single session)
+///
+/// Never lock a session while holding the registry: clone its handle out
+/// with `find_session` first, so a long step can't stall other connections.
+type SessionRegistry = Arc<Mutex<HashMap<String, SessionEntry>>>;
+
+/// Outbound queue for a single connection, shared with its tick tasks
+type Outbound = mpsc::Sender<ServerMessage>;
+
+/// Per-connection state owned by `handle_connection`
+struct Connection {
+    id: ConnectionId,
+    outbound: Outbound,
+    /// Last snapshot sent for each session created with `delta_updates`, or
+    /// `None` when its next update has to be sent in full
+    delta_baselines: HashMap<String, Option<GameStateSnapshot>>,
+    /// Sessions created over this connection
+    owned_sessions: Vec<String>,
+    /// Forwarding tasks for sessions this connection spectates
+    subscriptions: HashMap<String, JoinHandle<()>>,
+    /// Encoding used for outgoing messages
+    wire_format: WireFormat,
+    /// Whether large outgoing messages are sent as compressed frames
+    compress: bool,
+    /// `seq` of the last stamped message sent
+    last_seq: u64,
+    /// Sessions whose state updates go out as position frames
+    position_streams: HashSet<String>,
+    /// Whether the client may send anything besides `Authenticate`
+    authenticated: bool,
+    /// Server-wide counters, shared with every session this connection creates
+    metrics: Arc<Metrics>,
+    /// Server-wide seeds set by `SetGlobalSeed`
+    seeds: Arc<SeedSequence>,
+    /// Latest replies to requests that carried a `request_id`, oldest first
+    recent_replies: VecDeque<(String, ServerMessage)>,
+}
+
+impl Connection {
+    /// Final pass before a message is sent, swapping state updates for deltas
+    /// on sessions that asked for them
+    fn prepare(&mut self, msg: ServerMessage) -> ServerMessage {
+        match msg {
+            ServerMessage::StateUpdate { session_id, state } => {
+                match self.delta_baselines.get_mut(&session_id) {
+                    Some(Some(baseline)) => {
+                        let delta = delta::state_delta(session_id, baseline, &state);
+                        *baseline = state;
+                        delta
+                    }
+                    Some(baseline) => {
+                        *baseline = Some(state.clone());
+                        ServerMessage::StateUpdate { session_id, state }
+                    }
+                    None => ServerMessage::StateUpdate { session_id, state },
+                }
+            }
+            ServerMessage::SessionCreated {
+                ref session_id,
+                ref initial_state,
+                ..
+            } => {
+                if let Some(baseline) = self.delta_baselines.get_mut(session_id) {
+                    *baseline = Some(initial_state.clone());
+                }
+                msg
+            }
+            other => other,
+        }
+    }
+
+    /// The reply already given to a request with this id, if it's recent
+    /// enough to be remembered
+    fn cached_reply(&self, request_id: Option<&str>) -> Option<ServerMessage> {
+        let request_id = request_id?;
+        self.recent_replies
+            .iter()
+            .find(|(id, _)| id == request_id)
+            .map(|(_, reply)| reply.clone())
+    }
+
+    fn remember_reply(&mut self, request_id: Option<&str>, reply: &ServerMessage) {
+        let Some(request_id) = request_id else {
+            return;
+        };
+        if self.recent_replies.len() == REPLY_CACHE_SIZE {
+            self.recent_replies.pop_front();
+        }
+        self.recent_replies.push_back((request_id.to_string(), reply.clone()));
+    }
+
+    /// Wrap a message for sending, stamping state-bearing messages with the
+    /// next sequence number and the current time, and replies with the
+    /// `request_id` they answer
+    fn envelope(&mut self, message: ServerMessage, request_id: Option<String>) -> Envelope {
+        let stamped = matches!(
+            message,
+            ServerMessage::SessionCreated { .. }
+                | ServerMessage::StateUpdate { .. }
+                | ServerMessage::StateDelta { .. }
+                | ServerMessage::StateTrace { .. }
+        );
+        if !stamped {
+            return Envelope {
+                message,
+                seq: None,
+                server_time_ms: None,
+                request_id,
+            };
+        }
+
+        self.last_seq += 1;
+        let server_time_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+
+        Envelope {
+            message,
+            seq: Some(self.last_seq),
+            server_time_ms: Some(server_time_ms),
+            request_id,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    // RUST_LOG=debug also logs every message sent and received
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let config = match ServerConfig::load(std::env::args().skip(1)) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            error!("{}", e);
+            eprintln!("{}", config::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let started = Instant::now();
+    let addr = config.listen_addr();
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(%addr, error = %e, "Failed to bind");
+            std::process::exit(1);
+        }
+    };
+
+    info!(%addr, "Zombie Escape Server listening");
+
+    let sessions: SessionRegistry = Arc::new(Mutex::new(HashMap::new()));
+    let metrics = Arc::new(Metrics::default());
+    let seeds = Arc::new(SeedSequence::default());
+    if let Some(dir) = &config.save_dir {
+        restore_sessions(&sessions, dir, config.stuck_detection, &metrics);
+    }
+
+    if let Some(health_port) = config.health_port {
+        let health_addr = SocketAddr::new(config.bind, health_port);
+        match TcpListener::bind(health_addr).await {
+            Ok(listener) => {
+                info!(%health_addr, "Serving health checks");
+                tokio::spawn(http::serve(
+                    listener,
+                    Arc::clone(&sessions),
+                    Arc::clone(&metrics),
+                    started,
+                ));
+            }
+            Err(e) => {
+                error!(%health_addr, error = %e, "Failed to bind health check port");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    info!(idle_timeout = ?config.idle_timeout, "Evicting idle sessions");
+    tokio::spawn(reap_idle_sessions(Arc::clone(&sessions), Arc::clone(&config)));
+
+    // Set to the shutdown reason once the server starts stopping
+    let (shutdown_tx, shutdown_rx) = watch::channel(None::<String>);
+    let mut connections = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    info!(%peer, "New connection");
+                    connections.spawn(
+                        handle_connection(
+                            stream,
+                            Arc::clone(&sessions),
+                            Arc::clone(&config),
+                            Arc::clone(&metrics),
+                            Arc::clone(&seeds),
+                            shutdown_rx.clone(),
+                        )
+                        .instrument(info_span!("connection", %peer)),
+                    );
+                }
+                Err(e) => error!(error = %e, "Failed to accept connection"),
+            },
+            // Reap finished connection tasks
+            Some(_) = connections.join_next() => {}
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    info!(connections = connections.len(), "Shutting down");
+    let _ = shutdown_tx.send(Some("Server is shutting down".to_string()));
+
+    let drained = tokio::time::timeout(config.shutdown_grace, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        warn!("Some connections didn't close in time, dropping them");
+        connections.shutdown().await;
+    }
+
+    // Anything not already released by its connection
+    let remaining: Vec<String> = lock_registry(&sessions).keys().cloned().collect();
+    release_sessions(&sessions, &remaining);
+
+    info!("Server stopped");
+}
+
+/// Handshake callback settling the subprotocol, see `negotiate_subprotocol`
+struct SubprotocolHandshake;
+
+impl Callback for SubprotocolHandshake {
+    fn on_request(
+        self,
+        request: &Request,
+        mut response: Response,
+    ) -> Result<Response, ErrorResponse> {
+        match negotiate_subprotocol(request) {
+            Ok(agreed) => {
+                if let Some(name) = agreed {
+                    response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, name);
+                }
+                Ok(response)
+            }
+            Err(message) => {
+                warn!(%message, "Rejecting connection");
+                let mut error = ErrorResponse::new(Some(message));
+                *error.status_mut() = StatusCode::BAD_REQUEST;
+                Err(error)
+            }
+        }
+    }
+}
+
+/// The newest `Sec-WebSocket-Protocol` the client offers that this server
+/// speaks. Clients that offer none are accepted without one, as they always
+/// were; clients offering only others are turned away before the upgrade.
+fn negotiate_subprotocol(request: &Request) -> Result<Option<HeaderValue>, String> {
+    let offered: Vec<&str> = request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect();
+    if offered.is_empty() {
+        return Ok(None);
+    }
+
+    let agreed = (protocol::MIN_PROTOCOL_VERSION..=protocol::PROTOCOL_VERSION)
+        .rev()
+        .map(protocol::subprotocol)
+        .find(|name| offered.contains(&name.as_str()));
+    match agreed.and_then(|name| HeaderValue::from_str(&name).ok()) {
+        Some(name) => Ok(Some(name)),
+        None => Err(format!(
+            "Unsupported subprotocols {:?}, this server speaks {} to {}",
+            offered,
+            protocol::subprotocol(protocol::MIN_PROTOCOL_VERSION),
+            protocol::subprotocol(protocol::PROTOCOL_VERSION)
+        )),
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    sessions: SessionRegistry,
+    config: Arc<ServerConfig>,
+    metrics: Arc<Metrics>,
+    seeds: Arc<SeedSequence>,
+    mut shutdown: watch::Receiver<Option<String>>,
+) {
+    let ws_stream = match tokio_tungstenite::accept_hdr_async(stream, SubprotocolHandshake).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            error!(error = %e, "WebSocket handshake failed");
+            return;
+        }
+    };
+
+    info!("WebSocket connection established");
+
+    let (write, mut read) = ws_stream.split();
+    let send_queue = SendQueue::new(SEND_QUEUE_CAPACITY);
+    let mut writer = send_queue::spawn_writer(write, send_queue.clone());
+    let (outbound, mut pending) = mpsc::channel::<ServerMessage>(OUTBOUND_CAPACITY);
+    let mut connection = Connection {
+        id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+        outbound,
+        delta_baselines: HashMap::new(),
+        owned_sessions: Vec::new(),
+        subscriptions: HashMap::new(),
+        wire_format: WireFormat::Json,
+        compress: false,
+        last_seq: 0,
+        position_streams: HashSet::new(),
+        authenticated: config.auth_token.is_none(),
+        metrics,
+        seeds,
+        recent_replies: VecDeque::new(),
+    };
+
+    let mut heartbeat = tokio::time::interval_at(
+        Instant::now() + config.heartbeat_interval,
+        config.heartbeat_interval,
+    );
+    let mut last_pong = Instant::now();
+    // Set when the message about to be sent should be the last one
+    let mut close_after_send = false;
+    let mut limiter = (config.rate_limit > 0).then(|| TokenBucket::new(config.rate_limit));
+    // Set once the client has been told it's over the limit, until a message
+    // gets through again
+    let mut throttled = false;
+
+    loop {
+        // Set when the response answers a client request that carried an id
+        let mut request_id = None;
+        let response = tokio::select! {
+            msg = read.next() => {
+                // Parse client message
+                let parsed = match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        debug!(%text, "Received message");
+                        connection.metrics.record_received();
+                        WireFormat::Json.codec().decode(text.as_bytes())
+                    }
+                    Some(Ok(Message::Binary(bytes))) => {
+                        debug!(bytes = bytes.len(), "Received binary message");
+                        connection.metrics.record_received();
+                        protocol::decode_binary(connection.wire_format, &bytes)
+                    }
+                    Some(Ok(Message::Pong(_))) => {
+                        last_pong = Instant::now();
+                        continue;
+                    }
+                    Some(Ok(Message::Close(_))) | None => {
+                        info!("Client disconnected");
+                        break;
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => {
+                        error!(error = %e, "WebSocket error");
+                        break;
+                    }
+                };
+
+                if let Some(limiter) = &mut limiter
+                    && !limiter.try_take()
+                {
+                    if throttled {
+                        continue;
+                    }
+                    throttled = true;
+                    warn!(rate_limit = config.rate_limit, "Rate limit exceeded, dropping messages");
+
+                    ServerMessage::Error {
+                        message: format!(
+                            "Too many messages, the limit is {} per second",
+                            config.rate_limit
+                        ),
+                        code: ErrorCode::RateLimited,
+                    }
+                } else {
+                    throttled = false;
+
+                    match parsed {
+                        Ok(request)
+                            if connection.authenticated
+                                || matches!(
+                                    request.message,
+                                    ClientMessage::Authenticate { .. } | ClientMessage::Hello { .. }
+                                ) =>
+                        {
+                            request_id = request.request_id;
+                            if let Some(reply) = connection.cached_reply(request_id.as_deref()) {
+                                debug!(?request_id, "Repeating reply to a retried request");
+                                reply
+                            } else {
+                                let reply = handle_client_message(
+                                    request.message,
+                                    &sessions,
+                                    &config,
+                                    &mut connection,
+                                )
+                                .await;
+                                connection.remember_reply(request_id.as_deref(), &reply);
+                                reply
+                            }
+                        }
+                        Ok(request) => {
+                            request_id = request.request_id;
+                            ServerMessage::Error {
+                                message: "Send authenticate with the server's token first"
+                                    .to_string(),
+                                code: ErrorCode::Unauthorized,
+                            }
+                        }
+                        Err(DecodeError::Invalid(message)) => ServerMessage::Error {
+                            message,
+                            code: ErrorCode::ParseError,
+                        },
+                        Err(DecodeError::UnknownType { message_type, request_id: id }) => {
+                            debug!(%message_type, "Received unknown message type");
+                            request_id = id;
+                            ServerMessage::Error {
+                                message: message_type,
+                                code: ErrorCode::UnknownMessageType,
+                            }
+                        }
+                    }
+                }
+            }
+            // Messages pushed by this connection's tick tasks
+            Some(update) = pending.recv() => update,
+            Ok(()) = shutdown.changed() => {
+                close_after_send = true;
+                let reason = shutdown.borrow().clone().unwrap_or_default();
+                ServerMessage::ServerShutdown { reason }
+            }
+            _ = heartbeat.tick() => {
+                if last_pong.elapsed() > config.heartbeat_timeout {
+                    warn!(
+                        timeout = ?config.heartbeat_timeout,
+                        "No pong in time, dropping connection"
+                    );
+                    send_queue.push(Message::Close(None), Delivery::Required);
+                    break;
+                }
+
+                if !send_queue.push(Message::Ping(Default::default()), Delivery::Required) {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        // Clients that fail to authenticate don't get another try on this connection
+        if matches!(response, ServerMessage::Error { code: ErrorCode::Unauthorized, .. }) {
+            warn!("Closing unauthenticated connection");
+            close_after_send = true;
+        }
+
+        // Nor do clients speaking a protocol version we can't understand
+        if matches!(
+            response,
+            ServerMessage::Error { code: ErrorCode::ProtocolVersionUnsupported, .. }
+        ) {
+            warn!("Closing connection with unsupported protocol version");
+            close_after_send = true;
+        }
+
+        // Send response
+        connection.metrics.record_sent(&response);
+        if let ServerMessage::StateUpdate { session_id, state } = &response
+            && connection.position_streams.contains(session_id)
+        {
+            let frame = Message::Binary(state.to_position_frame().into());
+            let delivery =
+                if request_id.is_none() { Delivery::Droppable } else { Delivery::Required };
+            if !send_queue.push(frame, delivery) {
+                break;
+            }
+            continue;
+        }
+
+        // A client too slow for its deltas would grow the queue without
+        // limit, so drop them all and start each session over from a full
+        // update, this one first
+        if let ServerMessage::StateUpdate { session_id, .. } = &response
+            && request_id.is_none()
+            && connection.delta_baselines.contains_key(session_id)
+            && send_queue.is_full()
+        {
+            let dropped = send_queue.drop_deltas();
+            warn!(dropped, "Client isn't keeping up with deltas, resyncing with full state");
+            connection.delta_baselines.values_mut().for_each(|baseline| *baseline = None);
+        }
+
+        let response = connection.prepare(response);
+        // Updates pushed by tick loops can give way to newer ones. Deltas
+        // can't, since the next one builds on them, and neither can replies
+        // or the full updates deltas start from.
+        let delivery = match &response {
+            _ if request_id.is_some() => Delivery::Required,
+            ServerMessage::StateDelta { .. } => Delivery::Delta,
+            ServerMessage::StateUpdate { session_id, .. }
+                if !connection.delta_baselines.contains_key(session_id) =>
+            {
+                Delivery::Droppable
+            }
+            _ => Delivery::Required,
+        };
+        let response = connection.envelope(response, request_id);
+        let codec = connection.wire_format.codec();
+        let Some(response_bytes) = encode_envelope(codec, response) else {
+            continue;
+        };
+        let compressed = (connection.compress && compressed_frame::should_compress(&response_bytes))
+            .then(|| compressed_frame::compress(&response_bytes))
+            .and_then(|frame| {
+                // Still worth sending, just uncompressed
+                frame.inspect_err(|e| error!(error = %e, "Failed to compress message")).ok()
+            });
+        let frame = if let Some(frame) = compressed {
+            debug!(bytes = response_bytes.len(), compressed = frame.len(), "Sending message");
+            Message::Binary(frame.into())
+        } else if codec.is_binary() {
+            debug!(bytes = response_bytes.len(), codec = codec.name(), "Sending message");
+            Message::Binary(response_bytes.into())
+        } else {
+            let response_json = String::from_utf8(response_bytes).unwrap();
+            debug!(%response_json, "Sending message");
+            Message::Text(response_json.into())
+        };
+
+        if !send_queue.push(frame, delivery) {
+            break;
+        }
+
+        if close_after_send {
+            send_queue.push(Message::Close(None), Delivery::Required);
+            break;
+        }
+    }
+
+    // Let the writer flush what's queued, such as a final error and close
+    send_queue.close();
+    if tokio::time::timeout(SEND_DRAIN_TIMEOUT, &mut writer).await.is_err() {
+        warn!("Client isn't reading, dropping unsent messages");
+        writer.abort();
+    }
+
+    // Sessions die with the connection that created them, once nobody
+    // reattaches to them
+    detach_sessions(&sessions, &config, connection.id, &connection.owned_sessions);
+    for (_, subscription) in connection.subscriptions.drain() {
+        subscription.abort();
+    }
+
+    info!("Connection closed");
+}
+
+/// Encode a message for the wire, or an `encode_failed` error in its place
+/// so the client gets something it can parse rather than half a message.
+/// `None` if even that can't be encoded.
+fn encode_envelope(codec: &dyn Codec, envelope: Envelope) -> Option<Vec<u8>> {
+    match codec.encode(&envelope) {
+        Ok(bytes) => Some(bytes),
+        Err(e) => {
+            error!(error = %e, codec = codec.name(), "Failed to encode message");
+            let fallback = Envelope {
+                message: ServerMessage::Error {
+                    message: "The reply couldn't be encoded".to_string(),
+                    code: ErrorCode::EncodeFailed,
+                },
+                seq: None,
+                server_time_ms: None,
+                request_id: envelope.request_id,
+            };
+            codec.encode(&fallback).ok()
+        }
+    }
+}
+
+/// Periodically evict sessions nobody has touched within `idle_timeout`
+async fn reap_idle_sessions(sessions: SessionRegistry, config: Arc<ServerConfig>) {
+    let mut interval = tokio::time::interval(config.reap_interval);
+
+    loop {
+        interval.tick().await;
+
+        let handles: Vec<(String, Arc<Mutex<GameSession>>)> = lock_registry(&sessions)
+            .iter()
+            .map(|(session_id, entry)| (session_id.clone(), Arc::clone(&entry.session)))
+            .collect();
+
+        let idle: Vec<String> = handles
+            .into_iter()
+            .filter(|(_, session)| match session.lock() {
+                Ok(session) => session.last_activity.elapsed() >= config.idle_timeout,
+                // A poisoned session can't be used again, so reap it
+                Err(_) => true,
+            })
+            .map(|(session_id, _)| session_id)
+            .collect();
+
+        if !idle.is_empty() {
+            info!(
+                count = idle.len(),
+                idle_timeout = ?config.idle_timeout,
+                "Evicting idle sessions"
+            );
+            release_sessions(&sessions, &idle);
+            for session_id in &idle {
+                delete_save(&config, session_id);
+            }
+        }
+
+        let abandoned: Vec<String> = lock_registry(&sessions)
+            .iter()
+            .filter(|(_, entry)| {
+                entry
+                    .detached_at
+                    .is_some_and(|detached_at| detached_at.elapsed() >= config.reattach_grace)
+            })
+            .map(|(session_id, _)| session_id.clone())
+            .collect();
+
+        if !abandoned.is_empty() {
+            info!(count = abandoned.len(), "Releasing sessions nobody reattached to");
+            release_sessions(&sessions, &abandoned);
+        }
+    }
+}
+
+/// Reload every session saved in `dir`. They have no owner until closed or
+/// evicted.
+fn restore_sessions(
+    sessions: &SessionRegistry,
+    dir: &Path,
+    stuck_detection: StuckDetection,
+    metrics: &Arc<Metrics>,
+) {
+    let mut restored = Vec::new();
+
+    for saved in persistence::load_all::<GameConfig>(dir) {
+        let session_id = saved.session_id.clone();
+        if let Err(e) = validate_game_config(&saved.config) {
+            warn!(%session_id, error = %e, "Skipping saved session with invalid config");
+            continue;
+        }
+        if !(MIN_DT..=MAX_DT).contains(&saved.dt) {
+            warn!(%session_id, dt = saved.dt, "Skipping saved session with invalid dt");
+            continue;
+        }
+        if saved.step > MAX_RESTORE_STEPS {
+            warn!(%session_id, step = saved.step, "Skipping saved session too long to replay");
+            continue;
+        }
+
+        let session = match GameSession::restore(saved, stuck_detection, Arc::clone(metrics)) {
+            Ok(session) => session,
+            Err(e) => {
+                warn!(%session_id, error = %e, "Skipping saved session that can't be replayed");
+                continue;
+            }
+        };
+        info!(%session_id, step = session.state.current_step, "Restored session");
+        restored.push((session_id, session));
+    }
+
+    // Replayed first, so the registry is only locked for the inserts
+    let mut registry = lock_registry(sessions);
+    for (session_id, session) in restored {
+        let entry = SessionEntry {
+            session: Arc::new(Mutex::new(session)),
+            tick_task: None,
+            owner: None,
+            detached_at: None,
+        };
+        registry.insert(session_id, entry);
+    }
+}
+
+/// Delete a session's save file, if saving is on, once the session is gone
+/// for good
+fn delete_save(config: &ServerConfig, session_id: &str) {
+    let Some(dir) = &config.save_dir else {
+        return;
+    };
+
+    if let Err(e) = persistence::remove(dir, session_id) {
+        warn!(%session_id, error = %e, "Failed to delete save");
+    }
+}
+
+/// Remove sessions from the registry, stopping their tick loops
+fn release_sessions(sessions: &SessionRegistry, session_ids: &[String]) {
+    let mut sessions = lock_registry(sessions);
+
+    for session_id in session_ids {
+        if let Some(mut entry) = sessions.remove(session_id) {
+            entry.stop_ticking();
+            info!(%session_id, "Released session");
+        }
+    }
+}
+
+/// Let go of the sessions a closing connection owns. They wait
+/// `reattach_grace` for a `Reattach` before they're released, and their tick
+/// loops stop, since nobody is listening. Sessions another connection has
+/// reattached to since are left alone.
+fn detach_sessions(
+    sessions: &SessionRegistry,
+    config: &ServerConfig,
+    owner: ConnectionId,
+    session_ids: &[String],
+) {
+    let owned: Vec<String> = {
+        let registry = lock_registry(sessions);
+        session_ids
+            .iter()
+            .filter(|session_id| {
+                registry.get(*session_id).is_some_and(|entry| entry.owner == Some(owner))
+            })
+            .cloned()
+            .collect()
+    };
+
+    if config.reattach_grace.is_zero() {
+        release_sessions(sessions, &owned);
+        return;
+    }
+
+    let mut registry = lock_registry(sessions);
+    for session_id in &owned {
+        if let Some(entry) = registry.get_mut(session_id) {
+            entry.stop_ticking();
+            entry.owner = None;
+            entry.detached_at = Some(Instant::now());
+            info!(%session_id, grace = ?config.reattach_grace, "Session waiting for reattach");
+        }
+    }
+}
+
+/// SHA-256 of a reconnect token, so the token itself is only ever held by
+/// the client
+fn hash_token(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+/// Hand a session to `connection` if `reconnect_token` is the one issued
+/// when it was created, and subscribe the connection to its updates
+fn reattach(
+    sessions: &SessionRegistry,
+    config: &ServerConfig,
+    connection: &mut Connection,
+    session_id: String,
+    reconnect_token: &str,
+) -> ServerMessage {
+    if connection.owned_sessions.len() >= config.max_sessions_per_connection
+        && !connection.owned_sessions.contains(&session_id)
+    {
+        return ServerMessage::Error {
+            message: format!(
+                "A connection can have at most {} sessions, close one first",
+                config.max_sessions_per_connection
+            ),
+            code: ErrorCode::CapacityReached,
+        };
+    }
+
+    let mut updates = None;
+    let response = with_session(sessions, session_id, |session, session_id| {
+        let token_matches = session
+            .reconnect_token_hash
+            .is_some_and(|expected| tokens_match(&expected, &hash_token(reconnect_token)));
+        if !token_matches {
+            warn!(%session_id, "Reattach with a wrong reconnect token");
+            return ServerMessage::Error {
+                message: format!("Wrong reconnect token for session {}", session_id),
+                code: ErrorCode::InvalidReconnectToken,
+            };
+        }
+
+        updates = Some(session.spectators.subscribe());
+        ServerMessage::Reattached {
+            session_id,
+            state: session.get_snapshot(),
+            maze_grid: session.maze_grid(),
+        }
+    });
+    let (Some(updates), ServerMessage::Reattached { session_id, .. }) = (updates, &response)
+    else {
+        return response;
+    };
+
+    {
+        let mut registry = lock_registry(sessions);
+        let Some(entry) = registry.get_mut(session_id) else {
+            return session_not_found(session_id.clone());
+        };
+        // A loop still running for the old connection reports to nobody
+        entry.stop_ticking();
+        entry.owner = Some(connection.id);
+        entry.detached_at = None;
+    }
+
+    info!(%session_id, "Session reattached");
+
+    if !connection.owned_sessions.contains(session_id) {
+        connection.owned_sessions.push(session_id.clone());
+    }
+    let subscription = spawn_subscription(
+        session_id.clone(),
+        updates,
+        connection.id,
+        connection.outbound.clone(),
+    );
+    if let Some(previous) = connection.subscriptions.insert(session_id.clone(), subscription) {
+        previous.abort();
+    }
+
+    response
+}
+
+fn serialize_grid(
+    grid: &zombie_scape::Grid2D,
+    encoding: MazeEncoding,
+    coordinates: CoordinateConvention,
+) -> MazeGrid {
+    let height = grid.height();
+    let width = grid.width();
+
+    match encoding {
+        MazeEncoding::Strings => {
+            let mut result = Vec::new();
+
+            for y in 0..height {
+                let mut row = Vec::new();
+                for x in 0..width {
+                    let cell_type = if is_cell_walkable(grid, x, y) {
+                        "walkable"
+                    } else {
+                        "wall"
+                    };
+                    row.push(cell_type.to_string());
+                }
+                result.push(row);
+            }
+
+            MazeGrid::Strings(result)
+        }
+        MazeEncoding::Flat => MazeGrid::Flat {
+            width,
+            height,
+            cells: flat_cells(grid),
+        },
+        MazeEncoding::RunLength => {
+            let mut runs: Vec<(u8, u32)> = Vec::new();
+
+            for cell in flat_cells(grid) {
+                match runs.last_mut() {
+                    Some((value, count)) if *value == cell => *count += 1,
+                    _ => runs.push((cell, 1)),
+                }
+            }
+
+            MazeGrid::RunLength {
+                width,
+                height,
+                runs,
+            }
+        }
+        MazeEncoding::Bitset => MazeGrid::Bitset {
+            width,
+            height,
+            bits: pack_bits(&flat_cells(grid)),
+        },
+        MazeEncoding::Segments => MazeGrid::Segments {
+            width,
+            height,
+            segments: walls::wall_segments(grid)
+                .into_iter()
+                .map(|segment| WallSegment {
+                    start: coordinates.report(segment.start, grid.cell_size()),
+                    end: coordinates.report(segment.end, grid.cell_size()),
+                })
+                .collect(),
+        },
+    }
+}
+
+/// One bit per 0/1 cell, cell `i` in bit `i % 8` (least significant first)
+/// of byte `i / 8`, with the last byte's unused bits left 0
+fn pack_bits(cells: &[u8]) -> Vec<u8> {
+    let mut bits = vec![0u8; cells.len().div_ceil(8)];
+
+    for (index, cell) in cells.iter().enumerate() {
+        bits[index / 8] |= cell << (index % 8);
+    }
+
+    bits
+}
+
+/// Row-major cells, 0 = wall, 1 = walkable
+fn flat_cells(grid: &zombie_scape::Grid2D) -> Vec<u8> {
+    let mut cells = Vec::with_capacity(grid.width() * grid.height());
+
+    for y in 0..grid.height() {
+        for x in 0..grid.width() {
+            cells.push(is_cell_walkable(grid, x, y) as u8);
+        }
+    }
+
+    cells
+}
+
+fn walkable_cell_count(grid: &zombie_scape::Grid2D) -> usize {
+    (0..grid.height())
+        .flat_map(|y| (0..grid.width()).map(move |x| (x, y)))
+        .filter(|&(x, y)| is_cell_walkable(grid, x, y))
+        .count()
+}
+
+fn is_cell_walkable(grid: &zombie_scape::Grid2D, x: usize, y: usize) -> bool {
+    // Convert grid coordinates to world coordinates (center of cell)
+    let cell_size = grid.cell_size();
+    let world_x = (x as f32 + 0.5) * cell_size;
+    let world_y = (y as f32 + 0.5) * cell_size;
+    let pos = zombie_scape::Vector2D::from_coords(world_x, world_y);
+
+    grid.is_walkable(pos)
+}
+
+/// Spawn the auto-step loop for a session, pushing a `StateUpdate` after every tick.
+///
+/// The session's `step_mode` decides how many steps a tick runs, and is read
+/// on every tick so it can be changed while the loop runs. The loop ends on
+/// its own once the game reaches a terminal status or the owning connection
+/// goes away.
+fn spawn_tick_task(
+    session_id: String,
+    session: Arc<Mutex<GameSession>>,
+    tick_rate_hz: u32,
+    owner: ConnectionId,
+    outbound: Outbound,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let period = Duration::from_secs_f64(1.0 / tick_rate_hz as f64);
+        let mut interval = tokio::time::interval(period);
+        let mut last_tick = Instant::now();
+        // Wall-clock time not yet covered by steps, in `StepMode::WallClock`
+        let mut lag = Duration::ZERO;
+
+        loop {
+            interval.tick().await;
+            let now = Instant::now();
+            let elapsed = now - last_tick;
+            last_tick = now;
+
+            let (state, finished) = {
+                let Ok(mut session) = session.lock() else {
+                    error!(%session_id, "Session is poisoned, stopping tick loop");
+                    break;
+                };
+                if session.is_finished() {
+                    break;
+                }
+                if session.paused {
+                    lag = Duration::ZERO;
+                    continue;
+                }
+                let steps = match session.step_mode {
+                    StepMode::Strict => {
+                        lag = Duration::ZERO;
+                        1
+                    }
+                    StepMode::WallClock => {
+                        lag += elapsed;
+                        let dt = Duration::from_secs_f32(session.dt);
+                        let due = (lag.as_secs_f64() / dt.as_secs_f64()) as u32;
+                        lag = lag.saturating_sub(dt * due);
+                        due.min(MAX_CATCH_UP_STEPS)
+                    }
+                };
+                if steps == 0 {
+                    continue;
+                }
+                session.step(steps);
+                let state = session.get_snapshot();
+                session.publish(owner, &state);
+                (state, session.is_finished())
+            };
+
+            let update = ServerMessage::StateUpdate {
+                session_id: session_id.clone(),
+                state,
+            };
+            // Skipped if the connection is behind, since the next one
+            // supersedes it, except for the last
+            if finished {
+                let _ = outbound.send(update).await;
+                break;
+            }
+            if let Err(mpsc::error::TrySendError::Closed(_)) = outbound.try_send(update) {
+                break;
+            }
+        }
+
+        info!(%session_id, "Tick loop finished");
+    })
+}
+
+/// Forward a session's published snapshots to a spectating connection,
+/// except those the connection already received as replies
+fn spawn_subscription(
+    session_id: String,
+    mut updates: broadcast::Receiver<(ConnectionId, GameStateSnapshot)>,
+    spectator: ConnectionId,
+    outbound: Outbound,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let state = match updates.recv().await {
+                Ok((origin, _)) if origin == spectator => continue,
+                Ok((_, state)) => state,
+                // Slow spectator: older snapshots are superseded anyway
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                // The session was closed
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+
+            let update = ServerMessage::StateUpdate {
+                session_id: session_id.clone(),
+                state,
+            };
+            // A connection that's behind skips to the next snapshot
+            if let Err(mpsc::error::TrySendError::Closed(_)) = outbound.try_send(update) {
+                break;
+            }
+        }
+    })
+}
+
+/// Lock the registry, recovering it if a panicking thread poisoned the mutex.
+///
+/// Every registry update is a single map operation, so the map itself is
+/// never left half-modified.
+fn lock_registry(sessions: &SessionRegistry) -> MutexGuard<'_, HashMap<String, SessionEntry>> {
+    sessions.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Look up a session, releasing the registry lock before the caller locks it
+/// so one slow session never blocks the others
+fn find_session(sessions: &SessionRegistry, session_id: &str) -> Option<Arc<Mutex<GameSession>>> {
+    lock_registry(sessions)
+        .get(session_id)
+        .map(|entry| Arc::clone(&entry.session))
+}
+
+/// Run `f` on a locked session, marking it as active.
+///
+/// A session whose mutex was poisoned by a panic mid-step may hold a broken
+/// `GameState`, so it's reported as an error rather than used.
+fn with_session<F>(sessions: &SessionRegistry, session_id: String, f: F) -> ServerMessage
+where
+    F: FnOnce(&mut GameSession, String) -> ServerMessage,
+{
+    let Some(session) = find_session(sessions, &session_id) else {
+        return session_not_found(session_id);
+    };
+
+    let Ok(mut session) = session.lock() else {
+        return ServerMessage::Error {
+            message: format!("Session crashed and can no longer be used: {}", session_id),
+            code: ErrorCode::SessionPoisoned,
+        };
+    };
+
+    session.touch();
+    f(&mut session, session_id)
+}
+
+/// Mark a session as active for messages that don't otherwise lock it
+fn touch_session(sessions: &SessionRegistry, session_id: &str) {
+    if let Some(session) = find_session(sessions, session_id)
+        && let Ok(mut session) = session.lock()
+    {
+        session.touch();
+    }
+}
+
+fn session_not_found(session_id: String) -> ServerMessage {
+    ServerMessage::Error {
+        message: format!("Session not found: {}", session_id),
+        code: ErrorCode::SessionNotFound,
+    }
+}
+
+/// Store a newly built session for `connection` and describe it in a
+/// `SessionCreated`, unless the server or the connection is full
+fn register_session(
+    sessions: &SessionRegistry,
+    config: &ServerConfig,
+    connection: &mut Connection,
+    mut session: GameSession,
+    delta_updates: bool,
+) -> Result<ServerMessage, Box<ServerMessage>> {
+    let reconnect_token = Uuid::new_v4().simple().to_string();
+    session.reconnect_token_hash = Some(hash_token(&reconnect_token));
+
+    let session_id = session.id.clone();
+    let initial_state = session.get_snapshot();
+    let seed = session.state.config.maze_seed;
+    let resolved_config = GameConfigSnapshot::from(&session.state.config);
+
+    // Serialize the maze grid
+    let maze_grid = session.maze_grid();
+
+    let baseline = delta_updates.then(|| initial_state.clone());
+    insert_session(sessions, config, connection, session, baseline)?;
+
+    Ok(ServerMessage::SessionCreated {
+        session_id,
+        seed,
+        resolved_config,
+        initial_state,
+        maze_grid,
+        reconnect_token: Some(reconnect_token),
+    })
+}
+
+/// Add a new session to the registry, owned by `connection`, unless the
+/// server or the connection already has as many as allowed. `baseline` is
+/// the first state sent to a connection that wants deltas.
+fn insert_session(
+    sessions: &SessionRegistry,
+    config: &ServerConfig,
+    connection: &mut Connection,
+    session: GameSession,
+    baseline: Option<GameStateSnapshot>,
+) -> Result<(), Box<ServerMessage>> {
+    if connection.owned_sessions.len() >= config.max_sessions_per_connection {
+        return Err(Box::new(ServerMessage::Error {
+            message: format!(
+                "A connection can have at most {} sessions, close one first",
+                config.max_sessions_per_connection
+            ),
+            code: ErrorCode::CapacityReached,
+        }));
+    }
+
+    let session_id = session.id.clone();
+
+    // Store session, unless the server is full. Checked under the same
+    // lock as the insert so concurrent creates can't overshoot.
+    {
+        let mut registry = lock_registry(sessions);
+        if registry.len() >= config.max_sessions {
+            warn!(max_sessions = config.max_sessions, "Session limit reached");
+            return Err(Box::new(ServerMessage::Error {
+                message: "The server has no room for more sessions, try again later".to_string(),
+                code: ErrorCode::CapacityReached,
+            }));
+        }
+
+        let entry = SessionEntry {
+            session: Arc::new(Mutex::new(session)),
+            tick_task: None,
+            owner: Some(connection.id),
+            detached_at: None,
+        };
+        registry.insert(session_id.clone(), entry);
+    }
+
+    connection.owned_sessions.push(session_id.clone());
+    if let Some(baseline) = baseline {
+        connection.delta_baselines.insert(session_id.clone(), Some(baseline));
+    }
+
+    connection.metrics.record_session_created();
+    info!(%session_id, "Session created");
+
+    Ok(())
+}
+
+/// Check the parts of a `create_session` request that don't need the maze
+fn validate_session_settings(
+    game_config: &GameConfig,
+    dt: f32,
+    max_steps: Option<u64>,
+) -> Result<(), Box<ServerMessage>> {
+    if !(MIN_DT..=MAX_DT).contains(&dt) {
+        return Err(Box::new(invalid_dt(dt)));
+    }
+
+    if max_steps == Some(0) {
+        return Err(Box::new(ServerMessage::Error {
+            message: "max_steps must be at least 1".to_string(),
+            code: ErrorCode::ConfigInvalid,
+        }));
+    }
+
+    validate_game_config(game_config).map_err(|message| {
+        Box::new(ServerMessage::Error {
+            message,
+            code: ErrorCode::ConfigInvalid,
+        })
+    })
+}
+
+/// Reject a new session whose maze is too small for its agents, which need
+/// an open cell to stand in each, counting the fugitive
+fn check_room_for_agents(session: GameSession) -> Result<GameSession, Box<ServerMessage>> {
+    let open_cells = walkable_cell_count(&session.state.grid);
+    let zombie_count = session.state.config.zombie_count;
+    if zombie_count >= open_cells {
+        return Err(Box::new(ServerMessage::Error {
+            message: format!(
+                "zombie_count {} doesn't fit: the maze has {} open cells, room for {} \
+                 zombies besides the fugitive",
+                zombie_count,
+                open_cells,
+                open_cells.saturating_sub(1)
+            ),
+            code: ErrorCode::TooManyAgents,
+        }));
+    }
+
+    Ok(session)
+}
+
+/// Explain each zombie's step by comparing its snapshots from either side of
+/// it. Zombies despawned in between are skipped.
+fn zombie_decisions(before: &[AgentSnapshot], after: &[AgentSnapshot]) -> Vec<ZombieDecision> {
+    after
+        .iter()
+        .filter_map(|zombie| {
+            let zombie_id = zombie.id()?;
+            let previous = before.iter().find(|z| z.id() == Some(zombie_id))?;
+            let sighting_pos = zombie.last_seen_position();
+
+            Some(ZombieDecision {
+                zombie_id,
+                saw_fugitive: sighting_pos.is_some()
+                    && sighting_pos != previous.last_seen_position(),
+                sighting_pos,
+                previous_state: previous.state().unwrap_or_default().to_string(),
+                chose_state: zombie.state().unwrap_or_default().to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Capabilities advertised in `ServerInfo`
+fn server_features(config: &ServerConfig) -> Vec<String> {
+    let mut features = vec![
+        "msgpack",
+        "cbor",
+        "delta_updates",
+        "spectators",
+        "auto_step",
+        "position_frames",
+        "recording",
+        "replay_verification",
+        "path_queries",
+        "compression",
+    ];
+    if cfg!(feature = "schema") {
+        features.push("schema");
+    }
+    if config.allow_session_listing {
+        features.push("session_listing");
+    }
+    if config.auth_token.is_some() {
+        features.push("auth_required");
+    }
+
+    features.into_iter().map(str::to_string).collect()
+}
+
+/// Compare tokens without stopping at the first difference, so response
+/// timing doesn't reveal how much of a guess was right
+fn tokens_match(expected: &[u8], given: &[u8]) -> bool {
+    expected.len() == given.len()
+        && expected
+            .iter()
+            .zip(given)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn invalid_dt(dt: f32) -> ServerMessage {
+    ServerMessage::Error {
+        message: format!(
+            "Timestep must be between {} and {} seconds, got {}",
+            MIN_DT, MAX_DT, dt
+        ),
+        code: ErrorCode::InvalidDt,
+    }
+}
+
+fn steps_too_large(steps: u64, config: &ServerConfig) -> ServerMessage {
+    ServerMessage::Error {
+        message: format!(
+            "Cannot run {} steps in one request, the limit is {}",
+            steps, config.max_steps_per_request
+        ),
+        code: ErrorCode::StepsTooLarge,
+    }
+}
+
+/// Start (or restart) a session's auto-step loop, reporting to `connection`
+fn start_simulation(
+    sessions: &SessionRegistry,
+    session_id: String,
+    tick_rate_hz: u32,
+    connection: &Connection,
+) -> ServerMessage {
+    if !(MIN_TICK_RATE_HZ..=MAX_TICK_RATE_HZ).contains(&tick_rate_hz) {
+        return ServerMessage::Error {
+            message: format!(
+                "Tick rate must be between {} and {} Hz, got {}",
+                MIN_TICK_RATE_HZ, MAX_TICK_RATE_HZ, tick_rate_hz
+            ),
+            code: ErrorCode::InvalidTickRate,
+        };
+    }
+
+    info!(%session_id, tick_rate_hz, "Starting auto-step");
+
+    touch_session(sessions, &session_id);
+
+    let mut sessions = lock_registry(sessions);
+
+    match sessions.get_mut(&session_id) {
+        Some(entry) => {
+            // Restarting replaces any loop already running for this session
+            entry.stop_ticking();
+            entry.tick_task = Some(spawn_tick_task(
+                session_id.clone(),
+                Arc::clone(&entry.session),
+                tick_rate_hz,
+                connection.id,
+                connection.outbound.clone(),
+            ));
+
+            ServerMessage::SimulationStarted {
+                session_id,
+                tick_rate_hz,
+            }
+        }
+        None => session_not_found(session_id),
+    }
+}
+
+fn set_paused(
+    sessions: &SessionRegistry,
+    session_id: String,
+    paused: bool,
+    origin: ConnectionId,
+) -> ServerMessage {
+    let verb = if paused { "Pausing" } else { "Resuming" };
+    info!(%session_id, "{} session", verb);
+
+    with_session(sessions, session_id, |session, session_id| {
+        session.paused = paused;
+        let state = session.get_snapshot();
+        session.publish(origin, &state);
+
+        ServerMessage::StateUpdate { session_id, state }
+    })
+}
+
+async fn handle_client_message(
+    msg: ClientMessage,
+    sessions: &SessionRegistry,
+    config: &ServerConfig,
+    connection: &mut Connection,
+) -> ServerMessage {
+    match msg {
+        ClientMessage::Hello { protocol_version } => {
+            let supported = protocol::MIN_PROTOCOL_VERSION..=protocol::PROTOCOL_VERSION;
+            if let Some(version) = protocol_version
+                && !supported.contains(&version)
+            {
+                return ServerMessage::Error {
+                    message: format!(
+                        "Protocol version {} is not supported, this server speaks {} to {}",
+                        version,
+                        supported.start(),
+                        supported.end()
+                    ),
+                    code: ErrorCode::ProtocolVersionUnsupported,
+                };
+            }
+
+            ServerMessage::ServerInfo {
+                protocol_version: protocol::PROTOCOL_VERSION,
+                crate_version: env!("CARGO_PKG_VERSION").to_string(),
+                features: server_features(config),
+            }
+        }
+
+        #[cfg(feature = "schema")]
+        ClientMessage::GetSchema => ServerMessage::Schema {
+            schema: protocol::json_schema(),
+        },
+
+        #[cfg(not(feature = "schema"))]
+        ClientMessage::GetSchema => ServerMessage::Error {
+            message: "This server was built without the `schema` feature".to_string(),
+            code: ErrorCode::Forbidden,
+        },
+
+        ClientMessage::Authenticate { token } => {
+            let valid = config
+                .auth_token
+                .as_deref()
+                .is_none_or(|expected| tokens_match(expected.as_bytes(), token.as_bytes()));
+            if !valid {
+                return ServerMessage::Error {
+                    message: "Invalid token".to_string(),
+                    code: ErrorCode::Unauthorized,
+                };
+            }
+
+            connection.authenticated = true;
+            ServerMessage::Authenticated
+        }
+
+        ClientMessage::CreateSession {
+            config: mut game_config,
+            maze_encoding,
+            coordinates,
+            delta_updates,
+            dt,
+            protocol,
+            compress,
+            max_steps,
+            start_step,
+        } => {
+            if let Err(error) = validate_session_settings(&game_config, dt, max_steps) {
+                return *error;
+            }
+
+            if start_step > config.max_steps_per_request {
+                return steps_too_large(start_step as u64, config);
+            }
+
+            let seeds = Arc::clone(&connection.seeds);
+            let created = seeds.with_next(|seed| {
+                if let Some(seed) = seed {
+                    game_config.maze_seed = seed;
+                }
+
+                info!(config = ?game_config, "Creating session");
+
+                let session = GameSession::new(
+                    game_config,
+                    maze_encoding,
+                    dt,
+                    max_steps,
+                    config.stuck_detection,
+                    Arc::clone(&connection.metrics),
+                );
+                let mut session = check_room_for_agents(session)?;
+
+                session.coordinates = coordinates;
+                session.step(start_step);
+                register_session(sessions, config, connection, session, delta_updates)
+            });
+
+            let reply = match created {
+                Ok(reply) => reply,
+                Err(error) => return *error,
+            };
+
+            // Applies to this reply and everything after it on this connection
+            if let Some(protocol) = protocol {
+                connection.wire_format = protocol;
+            }
+            if compress {
+                connection.compress = true;
+            }
+
+            reply
+        }
+
+        ClientMessage::CreateSessions {
+            config: game_config,
+            count,
+            dt,
+            max_steps,
+        } => {
+            if let Err(error) = validate_session_settings(&game_config, dt, max_steps) {
+                return *error;
+            }
+
+            if !(1..=MAX_BATCH_SESSIONS).contains(&count) {
+                return ServerMessage::Error {
+                    message: format!(
+                        "count must be between 1 and {}, got {}",
+                        MAX_BATCH_SESSIONS, count
+                    ),
+                    code: ErrorCode::ConfigInvalid,
+                };
+            }
+
+            info!(config = ?game_config, count, "Creating sessions");
+
+            let seeds = Arc::clone(&connection.seeds);
+            let mut session_ids = Vec::new();
+            let mut error = None;
+            for offset in 0..count {
+                let inserted = seeds.with_next(|seed| {
+                    let mut game_config = game_config.clone();
+                    game_config.maze_seed = match seed {
+                        Some(seed) => seed,
+                        None => game_config.maze_seed.wrapping_add(offset as u64),
+                    };
+
+                    let session = GameSession::new(
+                        game_config,
+                        MazeEncoding::default(),
+                        dt,
+                        max_steps,
+                        config.stuck_detection,
+                        Arc::clone(&connection.metrics),
+                    );
+                    let session_id = session.id.clone();
+                    let session = check_room_for_agents(session)?;
+                    insert_session(sessions, config, connection, session, None)
+                        .map(|()| session_id)
+                });
+
+                let session_id = match inserted {
+                    Ok(session_id) => session_id,
+                    Err(failure) => {
+                        if let ServerMessage::Error { message, .. } = *failure {
+                            let created = session_ids.len();
+                            warn!(created, count, %message, "Batch cut short");
+                            error = Some(message);
+                        }
+                        break;
+                    }
+                };
+                session_ids.push(session_id);
+            }
+
+            ServerMessage::SessionsCreated { session_ids, error }
+        }
+
+        ClientMessage::StepSimulation { session_id, steps } => {
+            if steps > config.max_steps_per_request {
+                return steps_too_large(steps as u64, config);
+            }
+
+            debug!(%session_id, steps, "Stepping session");
+
+            with_session(sessions, session_id, |session, session_id| {
+                if session.paused {
+                    return ServerMessage::Error {
+                        message: format!("Session is paused: {}", session_id),
+                        code: ErrorCode::SessionPaused,
+                    };
+                }
+                session.step(steps);
+                let state = session.get_snapshot();
+                session.publish(connection.id, &state);
+
+                debug!(step = state.step, status = ?state.status, "Stepped session");
+
+                ServerMessage::StateUpdate { session_id, state }
+            })
+        }
+
+        ClientMessage::StepSimulationTrace {
+            session_id,
+            steps,
+            max_frames,
+        } => {
+            if steps > config.max_steps_per_request {
+                return steps_too_large(steps as u64, config);
+            }
+
+            debug!(%session_id, steps, max_frames, "Stepping session with trace");
+
+            let max_frames = max_frames.clamp(1, MAX_TRACE_FRAMES);
+            // Steps per frame, rounded up so there are never more than max_frames
+            let stride = steps.div_ceil(max_frames).max(1);
+
+            with_session(sessions, session_id, |session, session_id| {
+                if session.paused {
+                    return ServerMessage::Error {
+                        message: format!("Session is paused: {}", session_id),
+                        code: ErrorCode::SessionPaused,
+                    };
+                }
+
+                let mut frames = Vec::new();
+                let mut remaining = steps;
+                while remaining > 0 && !session.is_finished() {
+                    let chunk = remaining.min(stride);
+                    session.step(chunk);
+                    remaining -= chunk;
+                    frames.push(session.get_snapshot());
+                }
+
+                if let Some(last) = frames.last() {
+                    session.publish(connection.id, last);
+                }
+
+                ServerMessage::StateTrace { session_id, frames }
+            })
+        }
+
+        ClientMessage::RunToEnd {
+            session_id,
+            max_steps,
+        } => {
+            if max_steps > config.max_steps_per_request {
+                return steps_too_large(max_steps as u64, config);
+            }
+
+            debug!(%session_id, max_steps, "Running session to the end");
+
+            with_session(sessions, session_id, |session, session_id| {
+                if session.paused {
+                    return ServerMessage::Error {
+                        message: format!("Session is paused: {}", session_id),
+                        code: ErrorCode::SessionPaused,
+                    };
+                }
+
+                // step() stops by itself once the game is over
+                session.step(max_steps);
+                let state = session.get_snapshot();
+                session.publish(connection.id, &state);
+                let terminated = session.is_finished();
+
+                debug!(step = state.step, terminated, "Ran session");
+
+                ServerMessage::RunFinished {
+                    session_id,
+                    state,
+                    terminated,
+                }
+            })
+        }
+
+        ClientMessage::SetGlobalSeed { base_seed } => {
+            // Overrides the seed of every client's new sessions
+            if config.auth_token.is_none() {
+                return ServerMessage::Error {
+                    message: "A global seed needs a server that requires authentication"
+                        .to_string(),
+                    code: ErrorCode::Forbidden,
+                };
+            }
+
+            info!(base_seed, "Seeding new sessions from a global seed");
+            connection.seeds.set(base_seed);
+            ServerMessage::GlobalSeedSet { base_seed }
+        }
+
+        ClientMessage::GlobalStep { steps } => {
+            // Every client's sessions move, so only trusted clients may do it
+            if config.auth_token.is_none() {
+                return ServerMessage::Error {
+                    message: "Global steps need a server that requires authentication"
+                        .to_string(),
+                    code: ErrorCode::Forbidden,
+                };
+            }
+            if steps > config.max_steps_per_request {
+                return steps_too_large(steps as u64, config);
+            }
+
+            // Only hold the registry lock long enough to grab the handles,
+            // and each task only ever holds its own session's lock
+            let handles: Vec<(String, Arc<Mutex<GameSession>>)> = lock_registry(sessions)
+                .iter()
+                .map(|(session_id, entry)| (session_id.clone(), Arc::clone(&entry.session)))
+                .collect();
+
+            info!(steps, sessions = handles.len(), "Stepping every session");
+
+            let origin = connection.id;
+            let tasks: Vec<_> = handles
+                .into_iter()
+                .map(|(session_id, session)| {
+                    tokio::task::spawn_blocking(move || {
+                        let mut session = session.lock().ok()?;
+                        if !session.paused {
+                            session.step(steps);
+                        }
+                        let state = session.get_snapshot();
+                        session.publish(origin, &state);
+                        Some((session_id, state))
+                    })
+                })
+                .collect();
+
+            let mut updates = Vec::new();
+            for task in tasks {
+                // Poisoned sessions are left out
+                if let Ok(Some(update)) = task.await {
+                    updates.push(update);
+                }
+            }
+            updates.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            ServerMessage::GlobalStateUpdate { updates }
+        }
+
+        ClientMessage::StepDebug { session_id } => {
+            debug!(%session_id, "Stepping session with debug info");
+
+            with_session(sessions, session_id, |session, session_id| {
+                if session.paused {
+                    return ServerMessage::Error {
+                        message: format!("Session is paused: {}", session_id),
+                        code: ErrorCode::SessionPaused,
+                    };
+                }
+
+                let before = session.get_snapshot();
+                session.step(1);
+                let frame = session.get_snapshot();
+                session.publish(connection.id, &frame);
+
+                let reasons = zombie_decisions(&before.zombies, &frame.zombies);
+                ServerMessage::DebugStep {
+                    session_id,
+                    frame,
+                    reasons,
+                }
+            })
+        }
+
+        ClientMessage::AdvanceTime {
+            session_id,
+            seconds,
+        } => {
+            if !seconds.is_finite() || seconds < 0.0 {
+                return ServerMessage::Error {
+                    message: format!("Cannot advance by {} seconds", seconds),
+                    code: ErrorCode::InvalidDuration,
+                };
+            }
+
+            debug!(%session_id, seconds, "Advancing session");
+
+            with_session(sessions, session_id, |session, session_id| {
+                if session.paused {
+                    return ServerMessage::Error {
+                        message: format!("Session is paused: {}", session_id),
+                        code: ErrorCode::SessionPaused,
+                    };
+                }
+                let steps = ((session.time_accumulator + seconds) / session.dt).floor() as u64;
+                if steps > config.max_steps_per_request as u64 {
+                    return steps_too_large(steps, config);
+                }
+
+                session.advance_time(seconds);
+                let state = session.get_snapshot();
+                session.publish(connection.id, &state);
+
+                ServerMessage::StateUpdate { session_id, state }
+            })
+        }
+
+        ClientMessage::GetState { session_id } => {
+            with_session(sessions, session_id, |session, session_id| {
+                let state = session.get_snapshot();
+
+                ServerMessage::StateUpdate { session_id, state }
+            })
+        }
+
+        ClientMessage::GetObservation { session_id } => {
+            with_session(sessions, session_id, |session, session_id| {
+                let zombie_count = session.state.config.zombie_count;
+                let values = observation::observe(&session.get_snapshot(), zombie_count);
+
+                ServerMessage::Observation {
+                    session_id,
+                    values,
+                    layout: observation::layout(zombie_count),
+                }
+            })
+        }
+
+        ClientMessage::DumpState { session_id } => {
+            info!(%session_id, "Dumping session state");
+
+            with_session(sessions, session_id, |session, session_id| {
+                ServerMessage::StateDump {
+                    session_id,
+                    crate_version: env!("CARGO_PKG_VERSION").to_string(),
+                    seed: session.state.config.maze_seed,
+                    config: GameConfigSnapshot::from(&session.state.config),
+                    dt: session.dt,
+                    max_steps: session.max_steps,
+                    time_accumulator: session.time_accumulator,
+                    step_mode: session.step_mode,
+                    maze_encoding: session.maze_encoding,
+                    coordinates: session.coordinates,
+                    reward_shaping: session.reward_shaping,
+                    break_on_detection: session.break_on_detection,
+                    recording: session.recording.is_some(),
+                    recorded_frames: session
+                        .recording
+                        .as_ref()
+                        .map_or(0, |recording| recording.frames.len()),
+                    stuck_steps: session.stuck_detection.steps,
+                    stuck_distance: session.stuck_detection.distance,
+                    stuck: session.stuck,
+                    fugitive_history: session.fugitive_history.iter().copied().collect(),
+                    state: session.get_snapshot(),
+                    maze_grid: session.maze_grid(),
+                }
+            })
+        }
+
+        ClientMessage::GetMazeStats { session_id } => {
+            with_session(sessions, session_id, |session, session_id| {
+                let (start, exit) = (session.state.start_position(), session.state.exit_position());
+                let stats = maze_stats::maze_stats(
+                    &session.state.grid,
+                    [start.x(), start.y()],
+                    [exit.x(), exit.y()],
+                );
+
+                ServerMessage::MazeStats {
+                    session_id,
+                    walkable_cells: stats.walkable_cells,
+                    wall_cells: stats.wall_cells,
+                    shortest_path_len: stats.shortest_path_len,
+                    dead_ends: stats.dead_ends,
+                    branching_factor: stats.branching_factor,
+                }
+            })
+        }
+
+        ClientMessage::ExportMazeAscii { session_id } => {
+            with_session(sessions, session_id, |session, session_id| {
+                ServerMessage::MazeAscii {
+                    session_id,
+                    text: session.maze_ascii(),
+                }
+            })
+        }
+
+        ClientMessage::GetInterpolated { session_id, alpha } => {
+            let alpha = if alpha.is_nan() { 0.0 } else { alpha.clamp(0.0, 1.0) };
+
+            with_session(sessions, session_id, |session, session_id| {
+                let positions = session.interpolated_positions(alpha);
+
+                ServerMessage::Interpolated {
+                    session_id,
+                    step: session.state.current_step,
+                    alpha,
+                    fugitive: session.report(positions.fugitive),
+                    zombies: positions
+                        .zombies
+                        .into_iter()
+                        .map(|zombie| ZombiePosition {
+                            id: zombie.id,
+                            position: session.report(zombie.position),
+                        })
+                        .collect(),
+                }
+            })
+        }
+
+        ClientMessage::ResetSession { session_id } => {
+            info!(%session_id, "Resetting session");
+
+            with_session(sessions, session_id, |session, session_id| {
+                session.reset();
+                let initial_state = session.get_snapshot();
+                session.publish(connection.id, &initial_state);
+                let maze_grid = session.maze_grid();
+
+                info!(%session_id, "Session reset");
+
+                ServerMessage::SessionCreated {
+                    session_id,
+                    seed: session.state.config.maze_seed,
+                    resolved_config: GameConfigSnapshot::from(&session.state.config),
+                    initial_state,
+                    maze_grid,
+                    reconnect_token: None,
+                }
+            })
+        }
+
+        ClientMessage::ForkSession { session_id } => {
+            let Some(source) = find_session(sessions, &session_id) else {
+                return session_not_found(session_id);
+            };
+
+            // Only hold the source's lock long enough to read it; the replay
+            // runs on the copy
+            let fork = match source.lock() {
+                Ok(mut source) => {
+                    source.touch();
+                    source.fork()
+                }
+                Err(_) => {
+                    return ServerMessage::Error {
+                        message: format!(
+                            "Session crashed and can no longer be used: {}",
+                            session_id
+                        ),
+                        code: ErrorCode::SessionPoisoned,
+                    };
+                }
+            };
+
+            // The copy is rebuilt by replaying every step, so it's held to
+            // the same cap as a single step request
+            if fork.step > config.max_steps_per_request as u64 {
+                return steps_too_large(fork.step, config);
+            }
+
+            info!(%session_id, fork_id = %fork.session_id, step = fork.step, "Forking session");
+            let stuck_detection = config.stuck_detection;
+            let metrics = Arc::clone(&connection.metrics);
+            let replayed = tokio::task::spawn_blocking(move || {
+                GameSession::restore(fork, stuck_detection, metrics)
+            })
+            .await;
+
+            match replayed {
+                Ok(Ok(session)) => register_session(sessions, config, connection, session, false)
+                    .unwrap_or_else(|error| *error),
+                Ok(Err(message)) => ServerMessage::Error {
+                    message,
+                    code: ErrorCode::ForkFailed,
+                },
+                Err(_) => ServerMessage::Error {
+                    message: "Replay crashed the simulation".to_string(),
+                    code: ErrorCode::SessionPoisoned,
+                },
+            }
+        }
+
+        ClientMessage::DespawnZombie {
+            session_id,
+            zombie_index,
+        } => {
+            info!(%session_id, zombie_index, "Removing zombie");
+
+            with_session(sessions, session_id, |session, session_id| {
+                let zombie_count = session.state.zombies.len();
+                if zombie_index >= zombie_count {
+                    return ServerMessage::Error {
+                        message: format!(
+                            "Zombie index {} out of range, session has {} zombies",
+                            zombie_index, zombie_count
+                        ),
+                        code: ErrorCode::InvalidZombieIndex,
+                    };
+                }
+
+                session.despawn(zombie_index);
+                let state = session.get_snapshot();
+                session.publish(connection.id, &state);
+
+                ServerMessage::StateUpdate { session_id, state }
+            })
+        }
+
+        ClientMessage::GetMaze { session_id } => {
+            with_session(sessions, session_id, |session, session_id| {
+                ServerMessage::MazeData {
+                    session_id,
+                    maze_grid: session.maze_grid(),
+                    maze_info: session.maze_info(),
+                }
+            })
+        }
+
+        ClientMessage::GetConfig { session_id } => {
+            with_session(sessions, session_id, |session, session_id| {
+                ServerMessage::ConfigData {
+                    session_id,
+                    config: GameConfigSnapshot::from(&session.state.config),
+                    dt: session.dt,
+                    max_steps: session.max_steps,
+                }
+            })
+        }
+
+        ClientMessage::SubscribeSession { session_id } => {
+            info!(%session_id, "Subscribing to session");
+
+            let mut subscription = None;
+            let subscribed_id = session_id.clone();
+            let response = with_session(sessions, session_id, |session, session_id| {
+                subscription = Some(spawn_subscription(
+                    session_id.clone(),
+                    session.spectators.subscribe(),
+                    connection.id,
+                    connection.outbound.clone(),
+                ));
+
+                ServerMessage::Subscribed {
+                    session_id,
+                    state: session.get_snapshot(),
+                    maze_grid: session.maze_grid(),
+                }
+            });
+
+            // Subscribing again replaces the previous forwarder
+            if let Some(subscription) = subscription
+                && let Some(previous) = connection.subscriptions.insert(subscribed_id, subscription)
+            {
+                previous.abort();
+            }
+
+            response
+        }
+
+        ClientMessage::Reattach {
+            session_id,
+            reconnect_token,
+        } => {
+            info!(%session_id, "Reattaching to session");
+            reattach(sessions, config, connection, session_id, &reconnect_token)
+        }
+
+        ClientMessage::StartRecording { session_id } => {
+            info!(%session_id, "Starting recording");
+
+            with_session(sessions, session_id, |session, session_id| {
+                if session.recording.is_some() {
+                    return ServerMessage::Error {
+                        message: format!("Session is already recording: {}", session_id),
+                        code: ErrorCode::RecordingActive,
+                    };
+                }
+
+                // Events from before the recording started don't belong in it
+                let mut first_frame = session.get_snapshot();
+                first_frame.events.clear();
+                session.recording = Some(Recording {
+                    frames: vec![first_frame],
+                    truncated: false,
+                });
+
+                ServerMessage::RecordingStarted {
+                    session_id,
+                    step: session.state.current_step,
+                }
+            })
+        }
+
+        ClientMessage::StopRecording { session_id } => {
+            info!(%session_id, "Stopping recording");
+
+            with_session(sessions, session_id, |session, session_id| {
+                let Some(recording) = session.recording.take() else {
+                    return ServerMessage::Error {
+                        message: format!("Session is not recording: {}", session_id),
+                        code: ErrorCode::NotRecording,
+                    };
+                };
+
+                ServerMessage::Replay {
+                    session_id,
+                    seed: session.state.config.maze_seed,
+                    config: GameConfigSnapshot::from(&session.state.config),
+                    dt: session.dt,
+                    max_steps: session.max_steps,
+                    frames: recording.frames,
+                    truncated: recording.truncated,
+                }
+            })
+        }
+
+        ClientMessage::SaveSession { session_id } => {
+            let Some(dir) = &config.save_dir else {
+                return ServerMessage::Error {
+                    message: "Saving sessions is disabled on this server".to_string(),
+                    code: ErrorCode::Forbidden,
+                };
+            };
+
+            with_session(sessions, session_id, |session, session_id| {
+                if session.state.current_step > MAX_RESTORE_STEPS {
+                    return ServerMessage::Error {
+                        message: format!(
+                            "Session is at step {}, sessions past step {} can't be reloaded",
+                            session.state.current_step, MAX_RESTORE_STEPS
+                        ),
+                        code: ErrorCode::StepsTooLarge,
+                    };
+                }
+
+                match persistence::save(dir, &session.saved()) {
+                    Ok(path) => {
+                        info!(%session_id, path = %path.display(), "Saved session");
+                        ServerMessage::SessionSaved {
+                            session_id,
+                            path: path.display().to_string(),
+                        }
+                    }
+                    Err(e) => {
+                        error!(%session_id, error = %e, "Failed to save session");
+                        ServerMessage::Error {
+                            message: format!("Failed to save session {}: {}", session_id, e),
+                            code: ErrorCode::SaveFailed,
+                        }
+                    }
+                }
+            })
+        }
+
+        ClientMessage::CheckVisibility { session_id, from, to } => {
+            with_session(sessions, session_id, |session, session_id| {
+                let grid = &session.state.grid;
+                for (name, position) in [("from", from), ("to", to)] {
+                    if pathfinding::cell_at(grid, session.world(position)).is_none() {
+                        return ServerMessage::Error {
+                            message: format!("`{}` {:?} is outside the maze", name, position),
+                            code: ErrorCode::InvalidPosition,
+                        };
+                    }
+                }
+
+                let blocked_at =
+                    visibility::first_wall_hit(grid, session.world(from), session.world(to))
+                        .map(|position| session.report(position));
+
+                ServerMessage::Visibility {
+                    session_id,
+                    visible: blocked_at.is_none(),
+                    blocked_at,
+                }
+            })
+        }
+
+        ClientMessage::QueryPath { session_id, from, to } => {
+            with_session(sessions, session_id, |session, session_id| {
+                let grid = &session.state.grid;
+                for (name, position) in [("from", from), ("to", to)] {
+                    let walkable = pathfinding::cell_at(grid, session.world(position))
+                        .is_some_and(|(x, y)| is_cell_walkable(grid, x, y));
+                    if !walkable {
+                        return ServerMessage::Error {
+                            message: format!(
+                                "`{}` {:?} is outside the maze or inside a wall",
+                                name, position
+                            ),
+                            code: ErrorCode::InvalidPosition,
+                        };
+                    }
                 }
-                None => ServerMessage::Error {
-                    message: format!("Session not found: {}", session_id),
-                    code: "session_not_found".to_string(),
-                },
+
+                let path = pathfinding::shortest_path(grid, session.world(from), session.world(to))
+                    .map(|path| session.report_path(path));
+                let length = path.as_deref().map(pathfinding::path_length).unwrap_or(0.0);
+
+                ServerMessage::PathResult {
+                    session_id,
+                    path,
+                    length,
+                }
+            })
+        }
+
+        ClientMessage::VerifyReplay {
+            seed,
+            config: mut game_config,
+            dt,
+            expected_final_step,
+            expected_fugitive_pos,
+            tolerance,
+        } => {
+            game_config.maze_seed = seed;
+            if let Err(message) = validate_game_config(&game_config) {
+                return ServerMessage::Error {
+                    message,
+                    code: ErrorCode::ConfigInvalid,
+                };
+            }
+            if !(MIN_DT..=MAX_DT).contains(&dt) {
+                return invalid_dt(dt);
+            }
+            // Long reruns tie up a blocking thread, so anyone else gets the
+            // same cap as a single step request
+            if config.auth_token.is_none() {
+                if expected_final_step > config.max_steps_per_request as u64 {
+                    return steps_too_large(expected_final_step, config);
+                }
+            } else if expected_final_step > MAX_VERIFY_STEPS {
+                return ServerMessage::Error {
+                    message: format!(
+                        "Cannot verify {} steps, the limit is {}",
+                        expected_final_step, MAX_VERIFY_STEPS
+                    ),
+                    code: ErrorCode::StepsTooLarge,
+                };
+            }
+
+            info!(seed, expected_final_step, "Verifying replay");
+
+            // Never registered, so nobody else can see or step it
+            let rerun = tokio::task::spawn_blocking(move || {
+                rerun_game(game_config, dt, expected_final_step)
+            })
+            .await;
+            let Ok((final_step, fugitive_pos)) = rerun else {
+                return ServerMessage::Error {
+                    message: "Replay crashed the simulation".to_string(),
+                    code: ErrorCode::SessionPoisoned,
+                };
+            };
+
+            let [ex, ey] = expected_fugitive_pos;
+            let distance = ((fugitive_pos[0] - ex).powi(2) + (fugitive_pos[1] - ey).powi(2)).sqrt();
+
+            ServerMessage::ReplayVerified {
+                matches: final_step == expected_final_step && distance <= tolerance,
+                final_step,
+                fugitive_pos,
+                distance,
+            }
+        }
+
+        ClientMessage::Benchmark { config: game_config, steps, dt } => {
+            // Ties up a blocking thread for a while, so only trusted clients
+            // may do it
+            if config.auth_token.is_none() {
+                return ServerMessage::Error {
+                    message: "Benchmarks need a server that requires authentication".to_string(),
+                    code: ErrorCode::Forbidden,
+                };
+            }
+            if let Err(message) = validate_game_config(&game_config) {
+                return ServerMessage::Error {
+                    message,
+                    code: ErrorCode::ConfigInvalid,
+                };
+            }
+            if !(MIN_DT..=MAX_DT).contains(&dt) {
+                return invalid_dt(dt);
+            }
+            if steps > MAX_BENCHMARK_STEPS {
+                return ServerMessage::Error {
+                    message: format!(
+                        "Cannot benchmark {} steps, the limit is {}",
+                        steps, MAX_BENCHMARK_STEPS
+                    ),
+                    code: ErrorCode::StepsTooLarge,
+                };
+            }
+
+            info!(steps, "Running benchmark");
+
+            // Never registered, and off the async threads so other
+            // connections keep being served
+            let run =
+                tokio::task::spawn_blocking(move || benchmark_game(game_config, dt, steps)).await;
+            let Ok((steps, elapsed, final_status)) = run else {
+                return ServerMessage::Error {
+                    message: "Benchmark crashed the simulation".to_string(),
+                    code: ErrorCode::SessionPoisoned,
+                };
+            };
+
+            let seconds = elapsed.as_secs_f64();
+            info!(steps, ?elapsed, "Benchmark finished");
+
+            ServerMessage::BenchResult {
+                steps,
+                total_ms: seconds * 1000.0,
+                steps_per_second: if seconds > 0.0 { steps as f64 / seconds } else { 0.0 },
+                final_status,
+            }
+        }
+
+        ClientMessage::PauseSession { session_id } => {
+            set_paused(sessions, session_id, true, connection.id)
+        }
+
+        ClientMessage::ResumeSession { session_id } => {
+            set_paused(sessions, session_id, false, connection.id)
+        }
+
+        ClientMessage::SetBreakOnDetection {
+            session_id,
+            enabled,
+        } => {
+            info!(%session_id, enabled, "Setting break on detection");
+
+            with_session(sessions, session_id, |session, session_id| {
+                session.break_on_detection = enabled;
+                let state = session.get_snapshot();
+
+                ServerMessage::StateUpdate { session_id, state }
+            })
+        }
+
+        ClientMessage::StartSimulation {
+            session_id,
+            tick_rate_hz,
+        } => {
+            connection.position_streams.remove(&session_id);
+            start_simulation(sessions, session_id, tick_rate_hz, connection)
+        }
+
+        ClientMessage::StreamPositions {
+            session_id,
+            tick_rate_hz,
+        } => {
+            let response = start_simulation(sessions, session_id.clone(), tick_rate_hz, connection);
+            if matches!(response, ServerMessage::SimulationStarted { .. }) {
+                connection.position_streams.insert(session_id);
+            }
+            response
+        }
+
+        ClientMessage::StopSimulation { session_id } => {
+            info!(%session_id, "Stopping auto-step");
+
+            touch_session(sessions, &session_id);
+
+            let mut sessions = lock_registry(sessions);
+
+            connection.position_streams.remove(&session_id);
+
+            match sessions.get_mut(&session_id) {
+                Some(entry) => {
+                    entry.stop_ticking();
+                    ServerMessage::SimulationStopped { session_id }
+                }
+                None => session_not_found(session_id),
+            }
+        }
+
+        ClientMessage::SetStepMode { session_id, mode } => {
+            info!(%session_id, ?mode, "Setting step mode");
+
+            with_session(sessions, session_id, |session, session_id| {
+                session.step_mode = mode;
+
+                ServerMessage::StepModeSet { session_id, mode }
+            })
+        }
+
+        ClientMessage::SetRewardShaping { session_id, config } => {
+            info!(%session_id, ?config, "Setting reward shaping");
+
+            if let Some(config) = &config
+                && let Err(message) = config.validate()
+            {
+                return ServerMessage::Error {
+                    message,
+                    code: ErrorCode::ConfigInvalid,
+                };
             }
+
+            with_session(sessions, session_id, |session, session_id| {
+                session.reward_shaping = config;
+                session.reward = 0.0;
+
+                ServerMessage::RewardShapingSet { session_id, config }
+            })
         }
 
         ClientMessage::CloseSession { session_id } => {
-            println!("🗑️  Closing session {}", session_id);
+            info!(%session_id, "Closing session");
+
+            connection.owned_sessions.retain(|id| *id != session_id);
 
-            let mut sessions = sessions.lock().unwrap();
+            let mut sessions = lock_registry(sessions);
             match sessions.remove(&session_id) {
-                Some(_) => {
-                    println!("✅ Session {} closed", session_id);
-                    ServerMessage::StateUpdate {
-                        session_id: session_id.clone(),
-                        state: GameStateSnapshot {
-                            step: 0,
-                            status: zombie_scape::GameStatus::Running,
-                            fugitive: AgentSnapshot::Fugitive {
-                                position: [0.0, 0.0],
-                                velocity: [0.0, 0.0],
-                                current_path: None,
-                                vision_range: 0.0,
-                                vision_angle: 0.0
-                            },
-                            zombies: vec![],
-                            maze_info: MazeInfo {
-                                width: 0,
-                                height: 0,
-                                cell_size: 0.0,
-                                start_position: [0.0, 0.0],
-                                exit_position: [0.0, 0.0],
-                            },
-                        },
+                Some(mut entry) => {
+                    entry.stop_ticking();
+                    delete_save(config, &session_id);
+                    info!(%session_id, "Session closed");
+                    connection.delta_baselines.remove(&session_id);
+                    connection.position_streams.remove(&session_id);
+
+                    ServerMessage::SessionClosed {
+                        session_id,
+                        remaining_sessions: sessions.len(),
                     }
                 }
-                None => ServerMessage::Error {
-                    message: format!("Session not found: {}", session_id),
-                    code: "session_not_found".to_string(),
-                },
+                None => session_not_found(session_id),
+            }
+        }
+
+        ClientMessage::ListSessions { offset, limit } => {
+            if !config.allow_session_listing {
+                return ServerMessage::Error {
+                    message: "Session listing is disabled on this server".to_string(),
+                    code: ErrorCode::Forbidden,
+                };
+            }
+
+            // Only hold the registry lock long enough to grab the handles
+            let mut handles: Vec<(String, Arc<Mutex<GameSession>>)> = lock_registry(sessions)
+                .iter()
+                .map(|(session_id, entry)| (session_id.clone(), Arc::clone(&entry.session)))
+                .collect();
+            // By id, so pages stay put as sessions come and go
+            handles.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            let total = handles.len();
+            let limit = limit.unwrap_or(MAX_SESSION_PAGE).min(MAX_SESSION_PAGE);
+            let sessions = handles
+                .iter()
+                .skip(offset)
+                .take(limit)
+                .filter_map(|(_, session)| session.lock().ok().map(|session| session.summary()))
+                .collect();
+
+            ServerMessage::SessionList {
+                sessions,
+                total,
+                offset,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::prelude::{BASE64_STANDARD, Engine};
+
+    fn game_config() -> GameConfig {
+        GameConfig {
+            maze_width: 15,
+            maze_height: 15,
+            zombie_count: 3,
+            fugitive_speed: 80.0,
+            zombie_speed: 60.0,
+            capture_radius: 10.0,
+            maze_seed: 42,
+            cell_size: 40.0,
+            braid_probability: 0.3,
+        }
+    }
+
+    fn session() -> GameSession {
+        GameSession::new(
+            game_config(),
+            MazeEncoding::default(),
+            0.016,
+            None,
+            ServerConfig::default().stuck_detection,
+            Arc::default(),
+        )
+    }
+
+    fn registry_with(sessions: impl IntoIterator<Item = GameSession>) -> SessionRegistry {
+        let registry = SessionRegistry::default();
+        for session in sessions {
+            let entry = SessionEntry {
+                session: Arc::new(Mutex::new(session)),
+                tick_task: None,
+                owner: None,
+                detached_at: None,
+            };
+            let session_id = entry.session.lock().unwrap().id.clone();
+            lock_registry(&registry).insert(session_id, entry);
+        }
+        registry
+    }
+
+    fn connection(id: ConnectionId) -> Connection {
+        Connection {
+            id,
+            outbound: mpsc::channel(OUTBOUND_CAPACITY).0,
+            delta_baselines: HashMap::new(),
+            owned_sessions: Vec::new(),
+            subscriptions: HashMap::new(),
+            wire_format: WireFormat::Json,
+            compress: false,
+            last_seq: 0,
+            position_streams: HashSet::new(),
+            authenticated: true,
+            metrics: Arc::default(),
+            seeds: Arc::default(),
+            recent_replies: VecDeque::new(),
+        }
+    }
+
+    fn rejected(change: impl FnOnce(&mut GameConfig)) -> String {
+        let mut config = game_config();
+        change(&mut config);
+        validate_game_config(&config).expect_err("config should be rejected")
+    }
+
+    #[test]
+    fn game_config_rejects_maze_sizes_out_of_range() {
+        assert!(rejected(|c| c.maze_width = 0).starts_with("maze_width"));
+        assert!(rejected(|c| c.maze_height = 0).starts_with("maze_height"));
+        assert!(rejected(|c| c.maze_width = MAX_MAZE_SIZE + 1).starts_with("maze_width"));
+        assert!(rejected(|c| c.maze_height = MAX_MAZE_SIZE + 1).starts_with("maze_height"));
+    }
+
+    #[test]
+    fn game_config_rejects_bad_speeds() {
+        for bad in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY, -1.0] {
+            assert!(rejected(|c| c.fugitive_speed = bad).starts_with("fugitive_speed"));
+            assert!(rejected(|c| c.zombie_speed = bad).starts_with("zombie_speed"));
+        }
+    }
+
+    #[test]
+    fn game_config_rejects_bad_radii_and_sizes() {
+        for bad in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY, -1.0, 0.0] {
+            assert!(rejected(|c| c.capture_radius = bad).starts_with("capture_radius"));
+            assert!(rejected(|c| c.cell_size = bad).starts_with("cell_size"));
+        }
+    }
+
+    #[test]
+    fn game_config_rejects_braid_probability_outside_unit_range() {
+        for bad in [-0.1, 1.1, f32::NAN, f32::INFINITY] {
+            assert!(rejected(|c| c.braid_probability = bad).starts_with("braid_probability"));
+        }
+    }
+
+    #[test]
+    fn game_config_rejects_too_many_zombies() {
+        assert!(rejected(|c| c.zombie_count = MAX_ZOMBIES + 1).starts_with("zombie_count"));
+    }
+
+    #[test]
+    fn game_config_accepts_boundary_values() {
+        let config = GameConfig {
+            maze_width: MAX_MAZE_SIZE,
+            maze_height: 1,
+            zombie_count: MAX_ZOMBIES,
+            fugitive_speed: 0.0,
+            zombie_speed: 0.0,
+            capture_radius: f32::MIN_POSITIVE,
+            maze_seed: 0,
+            cell_size: f32::MIN_POSITIVE,
+            braid_probability: 1.0,
+        };
+        assert_eq!(validate_game_config(&config), Ok(()));
+
+        let config = GameConfig {
+            braid_probability: 0.0,
+            ..game_config()
+        };
+        assert_eq!(validate_game_config(&config), Ok(()));
+    }
+
+    /// Cells back out of a bitset, as a client reads it
+    fn unpack_bits(bits: &[u8], cells: usize) -> Vec<u8> {
+        (0..cells).map(|index| (bits[index / 8] >> (index % 8)) & 1).collect()
+    }
+
+    #[test]
+    fn bitset_round_trips_a_grid_that_isnt_whole_bytes() {
+        // 5x3: 15 cells, so the second byte only has 7 of its bits in use
+        let cells = [1, 0, 0, 1, 1, 0, 1, 0, 1, 1, 1, 1, 0, 0, 1];
+        let bits = pack_bits(&cells);
+
+        assert_eq!(bits, [0b0101_1001, 0b0100_1111]);
+        assert_eq!(bits[1] & 0b1000_0000, 0, "padding bit is set");
+        assert_eq!(unpack_bits(&bits, cells.len()), cells);
+
+        let grid = MazeGrid::Bitset {
+            width: 5,
+            height: 3,
+            bits,
+        };
+        let json = serde_json::to_value(&grid).unwrap();
+        let encoded = json["bits"].as_str().unwrap();
+        let decoded = BASE64_STANDARD.decode(encoded).unwrap();
+        assert_eq!(unpack_bits(&decoded, cells.len()), cells);
+    }
+
+    #[test]
+    fn bitset_packs_grids_smaller_than_a_byte() {
+        assert_eq!(pack_bits(&[1]), [0b0000_0001]);
+        assert_eq!(pack_bits(&[0]), [0]);
+        assert_eq!(pack_bits(&[]), Vec::<u8>::new());
+    }
+
+    fn fugitive_with(mut position: [f32; 2], mut velocity: [f32; 2]) -> AgentSnapshot {
+        let problems = sanitize_motion(&mut position, &mut velocity);
+        let mut fugitive = AgentSnapshot::Fugitive {
+            position,
+            velocity,
+            current_path: None,
+            target: None,
+            nearest_zombie_distance: None,
+            progress_to_exit: 0.5,
+            vision_angle: 1.0,
+            vision_range: 100.0,
+            error: None,
+        };
+        sanitize_agent(&mut fugitive, problems);
+        fugitive
+    }
+
+    #[test]
+    fn non_finite_position_and_velocity_are_zeroed_and_flagged() {
+        for bad in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let fugitive = fugitive_with([bad, 1.5], [2.0, bad]);
+            let AgentSnapshot::Fugitive { position, velocity, .. } = &fugitive else {
+                unreachable!();
+            };
+
+            assert_eq!(*position, [0.0, 1.5]);
+            assert_eq!(*velocity, [2.0, 0.0]);
+            let error = fugitive.error().expect("agent should be flagged");
+            assert!(error.contains(&format!("non-finite position [{:?}, 1.5]", bad)), "{error}");
+            assert!(error.contains(&format!("non-finite velocity [2.0, {:?}]", bad)), "{error}");
+        }
+    }
+
+    #[test]
+    fn finite_agent_is_left_alone() {
+        let fugitive = fugitive_with([1.0, 2.0], [3.0, 4.0]);
+
+        assert_eq!(fugitive.position(), [1.0, 2.0]);
+        assert_eq!(fugitive.error(), None);
+    }
+
+    #[test]
+    fn every_zombie_float_is_sanitized() {
+        let mut zombie = AgentSnapshot::Zombie {
+            id: 1,
+            position: [1.0, 1.0],
+            velocity: [0.0, 0.0],
+            state: "pursuit".to_string(),
+            alertness: f32::NAN,
+            vision_range: f32::INFINITY,
+            vision_angle: f32::NEG_INFINITY,
+            last_seen_position: Some([f32::NAN, 2.0]),
+            current_path: Some(vec![[1.0, 1.0], [f32::INFINITY, 3.0]]),
+            error: None,
+        };
+        sanitize_agent(&mut zombie, Vec::new());
+
+        let AgentSnapshot::Zombie {
+            alertness,
+            vision_range,
+            vision_angle,
+            last_seen_position,
+            current_path,
+            error,
+            ..
+        } = zombie
+        else {
+            unreachable!();
+        };
+        assert_eq!([alertness, vision_range, vision_angle], [0.0; 3]);
+        assert_eq!(last_seen_position, Some([0.0, 2.0]));
+        assert_eq!(current_path, Some(vec![[1.0, 1.0], [0.0, 3.0]]));
+        let error = error.expect("zombie should be flagged");
+        for name in ["alertness", "vision_range", "vision_angle", "last_seen_position"] {
+            assert!(error.contains(name), "{error} doesn't mention {name}");
+        }
+        assert!(error.contains("current_path point [inf, 3.0]"), "{error}");
+    }
+
+    #[test]
+    fn every_fugitive_float_is_sanitized() {
+        let mut fugitive = AgentSnapshot::Fugitive {
+            position: [1.0, 1.0],
+            velocity: [0.0, 0.0],
+            current_path: Some(vec![[f32::NAN, 1.0]]),
+            target: Some([1.0, f32::NEG_INFINITY]),
+            nearest_zombie_distance: Some(f32::INFINITY),
+            progress_to_exit: f32::NAN,
+            vision_angle: f32::NAN,
+            vision_range: f32::INFINITY,
+            error: None,
+        };
+        sanitize_agent(&mut fugitive, Vec::new());
+
+        let AgentSnapshot::Fugitive {
+            current_path,
+            target,
+            nearest_zombie_distance,
+            progress_to_exit,
+            vision_angle,
+            vision_range,
+            error,
+            ..
+        } = fugitive
+        else {
+            unreachable!();
+        };
+        assert_eq!(current_path, Some(vec![[0.0, 1.0]]));
+        assert_eq!(target, Some([1.0, 0.0]));
+        assert_eq!(nearest_zombie_distance, Some(0.0));
+        assert_eq!([progress_to_exit, vision_angle, vision_range], [0.0; 3]);
+        assert!(error.is_some());
+    }
+
+    #[test]
+    fn with_session_releases_the_registry_before_running() {
+        let session = session();
+        let session_id = session.id.clone();
+        let sessions = registry_with([session]);
+
+        let reply = with_session(&sessions, session_id, |_, _| {
+            // Other connections can still find and create sessions meanwhile
+            assert!(sessions.try_lock().is_ok(), "registry is still locked");
+            ServerMessage::Authenticated
+        });
+        assert!(matches!(reply, ServerMessage::Authenticated));
+    }
+
+    #[test]
+    fn with_session_reports_unknown_sessions() {
+        let reply = with_session(&registry_with([]), "nope".to_string(), |_, _| {
+            unreachable!("there's no session to run on")
+        });
+        assert!(matches!(reply, ServerMessage::Error { code: ErrorCode::SessionNotFound, .. }));
+    }
+
+    /// Poison `mutex` the way a panicking step would
+    fn poison<T: Send + 'static>(mutex: Arc<Mutex<T>>) {
+        let _ = std::thread::spawn(move || {
+            let _guard = mutex.lock();
+            panic!("poisoning the lock on purpose");
+        })
+        .join();
+    }
+
+    #[test]
+    fn poisoned_session_is_reported_not_used() {
+        let session = session();
+        let session_id = session.id.clone();
+        let sessions = registry_with([session]);
+        poison(find_session(&sessions, &session_id).unwrap());
+
+        let reply = with_session(&sessions, session_id, |_, _| {
+            unreachable!("a poisoned session mustn't be used")
+        });
+        assert!(matches!(reply, ServerMessage::Error { code: ErrorCode::SessionPoisoned, .. }));
+    }
+
+    #[test]
+    fn poisoned_registry_is_recovered() {
+        let sessions = registry_with([session()]);
+        poison(Arc::clone(&sessions));
+
+        assert!(sessions.is_poisoned());
+        assert_eq!(lock_registry(&sessions).len(), 1);
+    }
+
+    #[test]
+    fn session_settings_reject_dt_out_of_range() {
+        for dt in [0.0, MIN_DT / 2.0, MAX_DT * 2.0, -0.016, f32::NAN, f32::INFINITY] {
+            let error = validate_session_settings(&game_config(), dt, None).unwrap_err();
+            assert!(
+                matches!(*error, ServerMessage::Error { code: ErrorCode::InvalidDt, .. }),
+                "dt {dt} wasn't rejected as invalid_dt"
+            );
+        }
+    }
+
+    #[test]
+    fn session_settings_accept_dt_bounds() {
+        for dt in [MIN_DT, 0.016, MAX_DT] {
+            assert!(validate_session_settings(&game_config(), dt, None).is_ok(), "dt {dt}");
+        }
+    }
+
+    #[tokio::test]
+    async fn spectators_get_every_change_but_their_own() {
+        let session = session();
+        let (outbound, mut pending) = mpsc::channel(OUTBOUND_CAPACITY);
+        let spectator: ConnectionId = 2;
+        let subscription = spawn_subscription(
+            session.id.clone(),
+            session.spectators.subscribe(),
+            spectator,
+            outbound,
+        );
+
+        let mut state = session.get_snapshot();
+        state.step = 1;
+        session.publish(spectator, &state);
+        state.step = 2;
+        session.publish(1, &state);
+
+        let Some(ServerMessage::StateUpdate { state, .. }) = pending.recv().await else {
+            panic!("expected a state update");
+        };
+        assert_eq!(state.step, 2, "the spectator's own change was echoed back");
+
+        drop(session);
+        subscription.await.unwrap();
+        assert!(pending.recv().await.is_none());
+    }
+
+    #[test]
+    fn session_settings_reject_zero_max_steps() {
+        let error = validate_session_settings(&game_config(), 0.016, Some(0)).unwrap_err();
+        assert!(matches!(*error, ServerMessage::Error { code: ErrorCode::ConfigInvalid, .. }));
+        assert!(validate_session_settings(&game_config(), 0.016, Some(1)).is_ok());
+    }
+
+    #[test]
+    fn session_times_out_at_max_steps() {
+        let mut session = session();
+        session.max_steps = Some(3);
+
+        session.step(2);
+        assert!(session.end_reason.is_none());
+
+        session.step(5);
+        assert_eq!(session.state.current_step, 3, "stepped past the limit");
+        assert!(matches!(session.end_reason, Some(EndReason::Timeout)));
+    }
+
+    #[test]
+    fn insert_session_stops_at_the_per_connection_cap() {
+        let config = ServerConfig {
+            max_sessions_per_connection: 2,
+            ..ServerConfig::default()
+        };
+        let registry = SessionRegistry::default();
+        let mut connection = connection(1);
+
+        for _ in 0..2 {
+            insert_session(&registry, &config, &mut connection, session(), None).unwrap();
+        }
+        let error = insert_session(&registry, &config, &mut connection, session(), None)
+            .unwrap_err();
+
+        assert!(matches!(*error, ServerMessage::Error { code: ErrorCode::CapacityReached, .. }));
+        assert_eq!(connection.owned_sessions.len(), 2);
+        assert_eq!(lock_registry(&registry).len(), 2);
+    }
+
+    #[test]
+    fn insert_session_stops_at_the_server_cap() {
+        let config = ServerConfig {
+            max_sessions: 2,
+            ..ServerConfig::default()
+        };
+        let registry = SessionRegistry::default();
+
+        for id in 1..=2 {
+            insert_session(&registry, &config, &mut connection(id), session(), None).unwrap();
+        }
+        let mut late = connection(3);
+        let error = insert_session(&registry, &config, &mut late, session(), None).unwrap_err();
+
+        assert!(matches!(*error, ServerMessage::Error { code: ErrorCode::CapacityReached, .. }));
+        assert!(late.owned_sessions.is_empty());
+        assert_eq!(lock_registry(&registry).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn close_session_reports_how_many_are_left() {
+        let config = ServerConfig::default();
+        let registry = SessionRegistry::default();
+        let mut connection = connection(1);
+        for _ in 0..3 {
+            insert_session(&registry, &config, &mut connection, session(), None).unwrap();
+        }
+        let session_id = connection.owned_sessions[0].clone();
+
+        let close = ClientMessage::CloseSession {
+            session_id: session_id.clone(),
+        };
+        let reply = handle_client_message(close, &registry, &config, &mut connection).await;
+
+        let ServerMessage::SessionClosed {
+            session_id: closed,
+            remaining_sessions,
+        } = reply
+        else {
+            panic!("expected session_closed, got {reply:?}");
+        };
+        assert_eq!(closed, session_id);
+        assert_eq!(remaining_sessions, 2);
+        assert!(!connection.owned_sessions.contains(&session_id));
+
+        let close = ClientMessage::CloseSession { session_id };
+        let reply = handle_client_message(close, &registry, &config, &mut connection).await;
+        assert!(matches!(reply, ServerMessage::Error { code: ErrorCode::SessionNotFound, .. }));
+    }
+
+    #[tokio::test]
+    async fn run_to_end_is_capped_per_request() {
+        let config = ServerConfig {
+            max_steps_per_request: 10,
+            ..ServerConfig::default()
+        };
+        let session = session();
+        let session_id = session.id.clone();
+        let registry = registry_with([session]);
+        let mut connection = connection(1);
+
+        let run = ClientMessage::RunToEnd {
+            session_id: session_id.clone(),
+            max_steps: 11,
+        };
+        let reply = handle_client_message(run, &registry, &config, &mut connection).await;
+        assert!(matches!(reply, ServerMessage::Error { code: ErrorCode::StepsTooLarge, .. }));
+
+        let run = ClientMessage::RunToEnd {
+            session_id,
+            max_steps: 10,
+        };
+        let reply = handle_client_message(run, &registry, &config, &mut connection).await;
+        let ServerMessage::RunFinished { state, terminated, .. } = reply else {
+            panic!("expected run_finished, got {reply:?}");
+        };
+        assert_eq!(state.step, 10);
+        assert!(!terminated);
+    }
+
+    #[test]
+    fn room_for_agents_needs_an_open_cell_each() {
+        let mut session = session();
+        let open_cells = walkable_cell_count(&session.state.grid);
+
+        session.state.config.zombie_count = open_cells - 1;
+        let mut session = check_room_for_agents(session).expect("one cell left for the fugitive");
+
+        session.state.config.zombie_count = open_cells;
+        let Err(error) = check_room_for_agents(session) else {
+            panic!("no cell left for the fugitive");
+        };
+        assert!(matches!(*error, ServerMessage::Error { code: ErrorCode::TooManyAgents, .. }));
+    }
+
+    /// Encodes nothing but `encode_failed` errors, or nothing at all
+    struct FailingCodec {
+        encodes_fallback: bool,
+    }
+
+    impl Codec for FailingCodec {
+        fn name(&self) -> &'static str {
+            "failing"
+        }
+
+        fn is_binary(&self) -> bool {
+            false
+        }
+
+        fn encode(&self, envelope: &Envelope) -> Result<Vec<u8>, String> {
+            match envelope.message {
+                ServerMessage::Error { code: ErrorCode::EncodeFailed, .. }
+                    if self.encodes_fallback =>
+                {
+                    serde_json::to_vec(envelope).map_err(|e| e.to_string())
+                }
+                _ => Err("can't encode this".to_string()),
             }
         }
+
+        fn decode(&self, _bytes: &[u8]) -> Result<protocol::messages::Request, DecodeError> {
+            unreachable!("only used for encoding")
+        }
+    }
+
+    fn envelope(request_id: Option<&str>) -> Envelope {
+        Envelope {
+            message: ServerMessage::Authenticated,
+            seq: Some(7),
+            server_time_ms: None,
+            request_id: request_id.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn encode_envelope_passes_good_messages_through() {
+        let codec = WireFormat::Json.codec();
+        let bytes = encode_envelope(codec, envelope(Some("r1"))).unwrap();
+        assert_eq!(bytes, codec.encode(&envelope(Some("r1"))).unwrap());
+    }
+
+    #[test]
+    fn encode_envelope_falls_back_to_an_error_with_the_request_id() {
+        let codec = FailingCodec {
+            encodes_fallback: true,
+        };
+        let bytes = encode_envelope(&codec, envelope(Some("r1"))).unwrap();
+
+        let reply: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["code"], "encode_failed");
+        assert_eq!(reply["request_id"], "r1");
+        assert!(reply.get("seq").is_none());
+    }
+
+    #[test]
+    fn encode_envelope_gives_up_when_the_fallback_fails_too() {
+        let codec = FailingCodec {
+            encodes_fallback: false,
+        };
+        assert!(encode_envelope(&codec, envelope(None)).is_none());
+    }
+
+    #[tokio::test]
+    async fn dump_state_includes_debugging_and_stuck_state() {
+        let mut session = session();
+        session.break_on_detection = true;
+        session.step(3);
+        let session_id = session.id.clone();
+        let registry = registry_with([session]);
+
+        let dump = ClientMessage::DumpState { session_id };
+        let config = ServerConfig::default();
+        let reply = handle_client_message(dump, &registry, &config, &mut connection(1)).await;
+
+        let ServerMessage::StateDump {
+            break_on_detection,
+            recording,
+            recorded_frames,
+            stuck_steps,
+            stuck,
+            fugitive_history,
+            ..
+        } = reply
+        else {
+            panic!("expected state_dump, got {reply:?}");
+        };
+        assert!(break_on_detection);
+        assert!(!recording);
+        assert_eq!(recorded_frames, 0);
+        assert_eq!(stuck_steps, config.stuck_detection.steps);
+        assert!(!stuck);
+        assert_eq!(fugitive_history.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn reattach_needs_the_issued_reconnect_token() {
+        let mut session = session();
+        session.reconnect_token_hash = Some(hash_token("right"));
+        let session_id = session.id.clone();
+        let registry = registry_with([session]);
+        let config = ServerConfig::default();
+        let mut connection = connection(1);
+
+        let reply = reattach(&registry, &config, &mut connection, session_id.clone(), "wrong");
+        assert!(matches!(
+            reply,
+            ServerMessage::Error { code: ErrorCode::InvalidReconnectToken, .. }
+        ));
+
+        let reply = reattach(&registry, &config, &mut connection, session_id, "right");
+        assert!(matches!(reply, ServerMessage::Reattached { .. }), "got {reply:?}");
+    }
+
+    #[tokio::test]
+    async fn run_to_end_stops_once_the_fugitive_escapes() {
+        let mut session = session();
+        // The fugitive reaches the exit on the first step
+        session.state.status = GameStatus::FugitiveWon;
+        session.step(1);
+        let session_id = session.id.clone();
+        let registry = registry_with([session]);
+
+        let run = ClientMessage::RunToEnd {
+            session_id,
+            max_steps: 10,
+        };
+        let config = ServerConfig::default();
+        let reply = handle_client_message(run, &registry, &config, &mut connection(1)).await;
+
+        let ServerMessage::RunFinished { state, terminated, .. } = reply else {
+            panic!("expected run_finished, got {reply:?}");
+        };
+        assert!(terminated);
+        assert_eq!(state.step, 1, "kept stepping after the game ended");
+        assert!(matches!(state.end_reason, Some(EndReason::Escaped)));
     }
 }
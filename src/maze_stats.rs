@@ -0,0 +1,60 @@
+//! Layout statistics for `GetMazeStats`, for judging how hard a generated
+//! maze is
+
+use zombie_scape::Grid2D;
+
+use crate::is_cell_walkable;
+use crate::pathfinding;
+
+pub struct MazeStats {
+    pub walkable_cells: usize,
+    pub wall_cells: usize,
+    /// Length of the shortest route from `start` to `exit`, or `None` if walls
+    /// separate them
+    pub shortest_path_len: Option<f32>,
+    /// Walkable cells with a single walkable neighbour
+    pub dead_ends: usize,
+    /// Average number of walkable neighbours of a walkable cell: 2.0 for
+    /// nothing but corridors, more the more junctions there are
+    pub branching_factor: f32,
+}
+
+/// Statistics of `grid`, with the route measured between the world positions
+/// `start` and `exit`
+pub fn maze_stats(grid: &Grid2D, start: [f32; 2], exit: [f32; 2]) -> MazeStats {
+    let mut walkable_cells = 0;
+    let mut dead_ends = 0;
+    let mut open_neighbours = 0;
+
+    for y in 0..grid.height() {
+        for x in 0..grid.width() {
+            if !is_cell_walkable(grid, x, y) {
+                continue;
+            }
+
+            let neighbours = pathfinding::neighbours(grid, (x, y))
+                .filter(|&(nx, ny)| is_cell_walkable(grid, nx, ny))
+                .count();
+            walkable_cells += 1;
+            open_neighbours += neighbours;
+            if neighbours == 1 {
+                dead_ends += 1;
+            }
+        }
+    }
+
+    let shortest_path_len = pathfinding::shortest_path(grid, start, exit)
+        .map(|path| pathfinding::path_length(&path));
+    let branching_factor = match walkable_cells {
+        0 => 0.0,
+        cells => open_neighbours as f32 / cells as f32,
+    };
+
+    MazeStats {
+        walkable_cells,
+        wall_cells: grid.width() * grid.height() - walkable_cells,
+        shortest_path_len,
+        dead_ends,
+        branching_factor,
+    }
+}
@@ -0,0 +1,115 @@
+//! Server-wide counters, served in Prometheus text format on `/metrics`
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::protocol::{ErrorCode, ServerMessage};
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    sessions_created: AtomicU64,
+    steps: AtomicU64,
+    /// Total time spent stepping, in nanoseconds
+    step_time_ns: AtomicU64,
+    messages_received: AtomicU64,
+    messages_sent: AtomicU64,
+    errors: Mutex<HashMap<ErrorCode, u64>>,
+}
+
+impl Metrics {
+    pub fn record_session_created(&self) {
+        self.sessions_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `steps` steps ran, taking `elapsed` altogether
+    pub fn record_steps(&self, steps: u64, elapsed: Duration) {
+        self.steps.fetch_add(steps, Ordering::Relaxed);
+        self.step_time_ns.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a message sent to a client, and its error code if it's an error
+    pub fn record_sent(&self, message: &ServerMessage) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+
+        if let ServerMessage::Error { code, .. } = message {
+            let mut errors = self.errors.lock().unwrap_or_else(|e| e.into_inner());
+            *errors.entry(*code).or_default() += 1;
+        }
+    }
+
+    /// Everything in Prometheus text exposition format. `active_sessions` is
+    /// read from the registry by the caller.
+    pub fn render(&self, active_sessions: usize) -> String {
+        let mut out = String::new();
+        let counter = |out: &mut String, name: &str, help: &str, value: &AtomicU64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        };
+
+        let _ = writeln!(out, "# HELP zombie_sessions_active Sessions currently alive");
+        let _ = writeln!(out, "# TYPE zombie_sessions_active gauge");
+        let _ = writeln!(out, "zombie_sessions_active {}", active_sessions);
+
+        counter(
+            &mut out,
+            "zombie_sessions_created_total",
+            "Sessions created, including forks",
+            &self.sessions_created,
+        );
+        counter(
+            &mut out,
+            "zombie_steps_total",
+            "Simulation steps run across all sessions",
+            &self.steps,
+        );
+        counter(
+            &mut out,
+            "zombie_messages_received_total",
+            "Messages received from clients",
+            &self.messages_received,
+        );
+        counter(
+            &mut out,
+            "zombie_messages_sent_total",
+            "Messages sent to clients",
+            &self.messages_sent,
+        );
+
+        let _ = writeln!(out, "# HELP zombie_errors_total Error messages sent, by code");
+        let _ = writeln!(out, "# TYPE zombie_errors_total counter");
+        let errors = self.errors.lock().unwrap_or_else(|e| e.into_inner());
+        let mut errors: Vec<(String, u64)> = errors
+            .iter()
+            .map(|(code, count)| (error_code_name(*code), *count))
+            .collect();
+        errors.sort();
+        for (code, count) in errors {
+            let _ = writeln!(out, "zombie_errors_total{{code=\"{}\"}} {}", code, count);
+        }
+
+        let steps = self.steps.load(Ordering::Relaxed);
+        let step_time = self.step_time_ns.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(out, "# HELP zombie_step_duration_seconds Time spent per simulation step");
+        let _ = writeln!(out, "# TYPE zombie_step_duration_seconds summary");
+        let _ = writeln!(out, "zombie_step_duration_seconds_sum {}", step_time);
+        let _ = writeln!(out, "zombie_step_duration_seconds_count {}", steps);
+
+        out
+    }
+}
+
+/// The code as it appears on the wire, e.g. `session_not_found`
+fn error_code_name(code: ErrorCode) -> String {
+    match serde_json::to_value(code) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => format!("{:?}", code),
+    }
+}
@@ -0,0 +1,51 @@
+//! Flat numeric observations for reinforcement learning clients
+//!
+//! Packs a snapshot into a fixed-length vector so a client can feed it to a
+//! model as is. Zombies fill one slot each by id, so a zombie keeps its
+//! indices for the whole game and removing one zeroes its slot instead of
+//! shifting the others.
+
+use crate::is_pursuit;
+use crate::protocol::GameStateSnapshot;
+
+/// Values for each zombie slot: position relative to the fugitive, and
+/// whether it's pursuing
+const ZOMBIE_FIELDS: [&str; 3] = ["dx", "dy", "pursuing"];
+
+/// Name of each value in an observation of a game with `zombie_count`
+/// zombies at the start
+pub fn layout(zombie_count: usize) -> Vec<String> {
+    let mut layout: Vec<String> = ["fugitive_x", "fugitive_y", "fugitive_vx", "fugitive_vy"]
+        .into_iter()
+        .map(String::from)
+        .collect();
+    for id in 1..=zombie_count {
+        layout.extend(ZOMBIE_FIELDS.iter().map(|field| format!("zombie_{}_{}", id, field)));
+    }
+    layout.push("exit_distance".to_string());
+
+    layout
+}
+
+/// `state` as values in the order given by `layout(zombie_count)`
+pub fn observe(state: &GameStateSnapshot, zombie_count: usize) -> Vec<f32> {
+    let [fx, fy] = state.fugitive.position();
+    let [vx, vy] = state.fugitive.velocity();
+
+    let mut values = vec![fx, fy, vx, vy];
+    for id in 1..=zombie_count as u64 {
+        match state.zombies.iter().find(|zombie| zombie.id() == Some(id)) {
+            Some(zombie) => {
+                let [zx, zy] = zombie.position();
+                let pursuing = zombie.state().is_some_and(is_pursuit);
+                values.extend([zx - fx, zy - fy, if pursuing { 1.0 } else { 0.0 }]);
+            }
+            // Removed, so the slot stays empty
+            None => values.extend([0.0; ZOMBIE_FIELDS.len()]),
+        }
+    }
+    let [ex, ey] = state.maze_info.exit_position;
+    values.push((ex - fx).hypot(ey - fy));
+
+    values
+}
@@ -0,0 +1,93 @@
+//! Shortest routes through a session's maze for `QueryPath`
+//!
+//! Searches the walkable cells of the grid directly, moving between
+//! orthogonal neighbours, so it doesn't depend on engine internals.
+
+use std::collections::VecDeque;
+use zombie_scape::Grid2D;
+
+use crate::is_cell_walkable;
+
+/// Cell containing a world position, if it's inside the grid
+pub fn cell_at(grid: &Grid2D, position: [f32; 2]) -> Option<(usize, usize)> {
+    let [x, y] = position;
+    if !x.is_finite() || !y.is_finite() || x < 0.0 || y < 0.0 {
+        return None;
+    }
+
+    let cell_x = (x / grid.cell_size()) as usize;
+    let cell_y = (y / grid.cell_size()) as usize;
+    (cell_x < grid.width() && cell_y < grid.height()).then_some((cell_x, cell_y))
+}
+
+/// Shortest route between two walkable cells, from `from` through the
+/// centres of the cells in between to `to`, or `None` if walls separate them
+pub fn shortest_path(grid: &Grid2D, from: [f32; 2], to: [f32; 2]) -> Option<Vec<[f32; 2]>> {
+    let start = cell_at(grid, from)?;
+    let goal = cell_at(grid, to)?;
+    let width = grid.width();
+    let index = |(x, y): (usize, usize)| y * width + x;
+
+    // Breadth-first search; every move costs the same
+    let mut came_from: Vec<Option<(usize, usize)>> = vec![None; width * grid.height()];
+    let mut visited = vec![false; width * grid.height()];
+    let mut frontier = VecDeque::from([start]);
+    visited[index(start)] = true;
+
+    while let Some(cell) = frontier.pop_front() {
+        if cell == goal {
+            break;
+        }
+
+        for next in neighbours(grid, cell) {
+            if !visited[index(next)] && is_cell_walkable(grid, next.0, next.1) {
+                visited[index(next)] = true;
+                came_from[index(next)] = Some(cell);
+                frontier.push_back(next);
+            }
+        }
+    }
+
+    if !visited[index(goal)] {
+        return None;
+    }
+
+    let cell_size = grid.cell_size();
+    let mut path = vec![to];
+    let mut cell = came_from[index(goal)];
+    while let Some(current) = cell {
+        if current == start {
+            break;
+        }
+        path.push([
+            (current.0 as f32 + 0.5) * cell_size,
+            (current.1 as f32 + 0.5) * cell_size,
+        ]);
+        cell = came_from[index(current)];
+    }
+    path.push(from);
+    path.reverse();
+
+    Some(path)
+}
+
+/// Total length of a path's segments
+pub fn path_length(path: &[[f32; 2]]) -> f32 {
+    path.windows(2)
+        .map(|pair| ((pair[1][0] - pair[0][0]).powi(2) + (pair[1][1] - pair[0][1]).powi(2)).sqrt())
+        .sum()
+}
+
+/// Orthogonal neighbours of a cell that are inside the grid, walls included
+pub fn neighbours(grid: &Grid2D, (x, y): (usize, usize)) -> impl Iterator<Item = (usize, usize)> {
+    let (width, height) = (grid.width(), grid.height());
+
+    [
+        x.checked_sub(1).map(|x| (x, y)),
+        (x + 1 < width).then_some((x + 1, y)),
+        y.checked_sub(1).map(|y| (x, y)),
+        (y + 1 < height).then_some((x, y + 1)),
+    ]
+    .into_iter()
+    .flatten()
+}
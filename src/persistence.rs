@@ -0,0 +1,122 @@
+//! Saving sessions to disk so they survive a restart
+//!
+//! The engine's `GameState` isn't serializable, so a save only records how
+//! the game was created and how far it got. Stepping is deterministic, so
+//! loading rebuilds the game from its config and replays it to that step.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::protocol::{CoordinateConvention, MazeEncoding};
+
+/// Contents of a save file. Written with a `GameConfigSnapshot` and read
+/// back as the `GameConfig` it mirrors.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedSession<C> {
+    pub session_id: String,
+    /// Includes the maze seed the game is rebuilt from
+    pub config: C,
+    pub dt: f32,
+    pub max_steps: Option<u64>,
+    pub maze_encoding: MazeEncoding,
+    #[serde(default)]
+    pub coordinates: CoordinateConvention,
+    /// Step the session had reached when it was saved
+    pub step: u64,
+    /// Zombies removed along the way, in the order they were removed
+    #[serde(default)]
+    pub despawns: Vec<Despawn>,
+}
+
+/// A zombie removed by `DespawnZombie`, which a replay has to remove again
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Despawn {
+    /// Step the session was at when the zombie was removed
+    pub step: u64,
+    /// Index among the zombies left at that point
+    pub zombie_index: usize,
+}
+
+/// Write a save into `dir`, replacing any earlier save of the same session,
+/// and return the file's path
+pub fn save<C: Serialize>(dir: &Path, saved: &SavedSession<C>) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+
+    let path = save_path(dir, &saved.session_id);
+    let contents = serde_json::to_vec_pretty(saved).map_err(io::Error::other)?;
+
+    // Write then rename, so a crash mid-write can't leave a truncated save
+    let partial = path.with_extension("json.partial");
+    fs::write(&partial, contents)?;
+    fs::rename(&partial, &path)?;
+
+    Ok(path)
+}
+
+/// Delete the save of `session_id` from `dir`, if it has one
+pub fn remove(dir: &Path, session_id: &str) -> io::Result<()> {
+    match fs::remove_file(save_path(dir, session_id)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn save_path(dir: &Path, session_id: &str) -> PathBuf {
+    dir.join(format!("{}.json", session_id))
+}
+
+/// Every readable save in `dir`. Unreadable files are logged and skipped.
+pub fn load_all<C: for<'de> Deserialize<'de>>(dir: &Path) -> Vec<SavedSession<C>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            tracing::error!(dir = %dir.display(), error = %e, "Failed to read save directory");
+            return Vec::new();
+        }
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|path| {
+            let loaded = fs::read(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|contents| serde_json::from_slice(&contents).map_err(|e| e.to_string()));
+
+            match loaded {
+                Ok(saved) => Some(saved),
+                Err(error) => {
+                    tracing::warn!(path = %path.display(), error, "Skipping unreadable save");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_from_before_the_seed_was_dropped_still_load() {
+        let saved: SavedSession<serde_json::Value> = serde_json::from_value(serde_json::json!({
+            "session_id": "old",
+            "seed": 7,
+            "config": { "maze_seed": 7 },
+            "dt": 0.016,
+            "max_steps": null,
+            "maze_encoding": "strings",
+            "step": 3,
+        }))
+        .unwrap();
+
+        assert_eq!(saved.config["maze_seed"], 7);
+        assert_eq!(saved.step, 3);
+        assert!(saved.despawns.is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+//! Encoding of protocol messages for each `WireFormat`
+
+use serde::Deserialize;
+
+use super::messages::{ClientMessage, Envelope, Request, WireFormat};
+
+/// Turns server messages into frame payloads and client payloads back into
+/// messages
+pub trait Codec: Sync {
+    /// Short name used in logs and error messages
+    fn name(&self) -> &'static str;
+
+    /// Whether payloads go in binary frames rather than text frames
+    fn is_binary(&self) -> bool;
+
+    fn encode(&self, message: &Envelope) -> Result<Vec<u8>, String>;
+
+    fn decode(&self, bytes: &[u8]) -> Result<Request, DecodeError>;
+}
+
+/// Why a client payload couldn't be decoded
+#[derive(Debug)]
+pub enum DecodeError {
+    /// Not a well-formed message
+    Invalid(String),
+    /// A well-formed message whose `type` this server doesn't know, most
+    /// likely from a client built for a newer protocol
+    UnknownType {
+        message_type: String,
+        request_id: Option<String>,
+    },
+}
+
+/// Just the envelope fields of a message, read when the full message fails
+/// to decode
+#[derive(Deserialize)]
+struct Probe {
+    #[serde(rename = "type")]
+    message_type: String,
+    #[serde(default)]
+    request_id: Option<String>,
+}
+
+impl DecodeError {
+    /// `error` if the payload is malformed, or `UnknownType` if `probe`
+    /// found a `type` that isn't a `ClientMessage`
+    fn new(error: String, probe: Option<Probe>) -> Self {
+        match probe {
+            Some(probe) if !is_known_type(&probe.message_type) => DecodeError::UnknownType {
+                message_type: probe.message_type,
+                request_id: probe.request_id,
+            },
+            _ => DecodeError::Invalid(error),
+        }
+    }
+}
+
+/// Whether `message_type` names a `ClientMessage` variant. A message with
+/// only the tag either decodes or fails on a missing field, and only an
+/// unknown tag fails as an unknown variant.
+fn is_known_type(message_type: &str) -> bool {
+    match serde_json::from_value::<ClientMessage>(serde_json::json!({ "type": message_type })) {
+        Ok(_) => true,
+        Err(e) => !e.to_string().starts_with("unknown variant"),
+    }
+}
+
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn name(&self) -> &'static str {
+        "JSON"
+    }
+
+    fn is_binary(&self) -> bool {
+        false
+    }
+
+    fn encode(&self, message: &Envelope) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(message).map_err(|e| e.to_string())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Request, DecodeError> {
+        serde_json::from_slice(bytes).map_err(|e| {
+            DecodeError::new(format!("Invalid JSON: {}", e), serde_json::from_slice(bytes).ok())
+        })
+    }
+}
+
+/// MessagePack with named fields, so maps mirror the JSON objects
+pub struct MsgpackCodec;
+
+impl Codec for MsgpackCodec {
+    fn name(&self) -> &'static str {
+        "MessagePack"
+    }
+
+    fn is_binary(&self) -> bool {
+        true
+    }
+
+    fn encode(&self, message: &Envelope) -> Result<Vec<u8>, String> {
+        rmp_serde::to_vec_named(message).map_err(|e| e.to_string())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Request, DecodeError> {
+        rmp_serde::from_slice(bytes).map_err(|e| {
+            let probe = rmp_serde::from_slice(bytes).ok();
+            DecodeError::new(format!("Invalid MessagePack: {}", e), probe)
+        })
+    }
+}
+
+pub struct CborCodec;
+
+impl Codec for CborCodec {
+    fn name(&self) -> &'static str {
+        "CBOR"
+    }
+
+    fn is_binary(&self) -> bool {
+        true
+    }
+
+    fn encode(&self, message: &Envelope) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(message, &mut bytes).map_err(|e| e.to_string())?;
+        Ok(bytes)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Request, DecodeError> {
+        ciborium::from_reader(bytes).map_err(|e| {
+            DecodeError::new(format!("Invalid CBOR: {}", e), ciborium::from_reader(bytes).ok())
+        })
+    }
+}
+
+impl WireFormat {
+    pub fn codec(self) -> &'static dyn Codec {
+        match self {
+            WireFormat::Json => &JsonCodec,
+            WireFormat::Msgpack => &MsgpackCodec,
+            WireFormat::Cbor => &CborCodec,
+        }
+    }
+}
+
+/// Decode a client request from a binary frame.
+///
+/// A connection that hasn't picked a binary format yet may send either
+/// MessagePack or CBOR; the two can't be mistaken for each other, since a
+/// CBOR map header reads as a MessagePack string.
+pub fn decode_binary(format: WireFormat, bytes: &[u8]) -> Result<Request, DecodeError> {
+    match format {
+        WireFormat::Json => MsgpackCodec.decode(bytes).or_else(|msgpack_error| {
+            match CborCodec.decode(bytes) {
+                Err(DecodeError::Invalid(_)) => Err(msgpack_error),
+                decoded => decoded,
+            }
+        }),
+        format => format.codec().decode(bytes),
+    }
+}
@@ -0,0 +1,59 @@
+//! Gzipped frames for connections that asked for compression
+//!
+//! A compressed frame is a binary frame holding `COMPRESSED_FRAME_TAG`
+//! followed by a gzip stream. Unzipped, it's exactly the payload the message
+//! would otherwise have been sent as in the connection's wire format: UTF-8
+//! JSON text, MessagePack or CBOR.
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::io::{self, Write};
+
+/// First byte of every compressed frame. It's neither a MessagePack or CBOR
+/// map nor `POSITION_FRAME_TAG`, so clients can tell the frames apart.
+pub const COMPRESSED_FRAME_TAG: u8 = 0x47;
+
+/// Payloads smaller than this are sent as they are, since gzip's header and
+/// the time spent would outweigh the savings
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Whether a connection that asked for compression sends `payload` as a
+/// compressed frame
+pub fn should_compress(payload: &[u8]) -> bool {
+    payload.len() >= COMPRESSION_THRESHOLD
+}
+
+pub fn compress(payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(vec![COMPRESSED_FRAME_TAG], Compression::fast());
+    encoder.write_all(payload)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn compressed_frame_round_trips() {
+        let payload = r#"{"type":"state_update","step":1}"#.repeat(64).into_bytes();
+        assert!(should_compress(&payload));
+
+        let frame = compress(&payload).unwrap();
+        let (&tag, gzipped) = frame.split_first().unwrap();
+        assert_eq!(tag, COMPRESSED_FRAME_TAG);
+        assert!(gzipped.len() < payload.len());
+
+        let mut unzipped = Vec::new();
+        GzDecoder::new(gzipped).read_to_end(&mut unzipped).unwrap();
+        assert_eq!(unzipped, payload);
+    }
+
+    #[test]
+    fn small_payloads_are_sent_uncompressed() {
+        assert!(!should_compress(&[]));
+        assert!(!should_compress(&[b'x'; COMPRESSION_THRESHOLD - 1]));
+        assert!(should_compress(&[b'x'; COMPRESSION_THRESHOLD]));
+    }
+}
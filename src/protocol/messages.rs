@@ -1,50 +1,658 @@
-use serde::{Deserialize, Serialize};
+use base64::prelude::{BASE64_STANDARD, Engine};
+use serde::{Deserialize, Serialize, Serializer};
 use zombie_scape::{GameConfig, GameStatus};
 
+/// A client message as received on the wire, with optional per-request
+/// metadata
+#[derive(Debug, Clone, Deserialize)]
+pub struct Request {
+    /// Echoed back on the reply; a repeated id gets the original reply again
+    /// instead of being handled twice
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub message: ClientMessage,
+}
+
 /// Client → Server messages
 #[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ClientMessage {
-    CreateSession { config: GameConfig },
+    /// Must come first when the server requires a token
+    Authenticate { token: String },
+    /// Ask what this server supports; allowed before authenticating.
+    /// Clients that send the `protocol_version` they speak are turned away
+    /// if the server doesn't support it.
+    Hello {
+        #[serde(default)]
+        protocol_version: Option<u32>,
+    },
+    /// JSON Schema for the protocol; needs the server built with the
+    /// `schema` feature
+    GetSchema,
+    CreateSession {
+        #[cfg_attr(feature = "schema", schemars(with = "GameConfigSnapshot"))]
+        config: GameConfig,
+        /// Format for `maze_grid` in responses; defaults to the string grid
+        #[serde(default)]
+        maze_encoding: MazeEncoding,
+        /// Where reported positions put the origin of a cell
+        #[serde(default)]
+        coordinates: CoordinateConvention,
+        /// Send `StateDelta` instead of `StateUpdate` after the first snapshot
+        #[serde(default)]
+        delta_updates: bool,
+        /// Simulated seconds per step
+        #[serde(default = "default_dt")]
+        dt: f32,
+        /// Switch this connection's outgoing messages to another encoding
+        #[serde(default)]
+        protocol: Option<WireFormat>,
+        /// Gzip this connection's large outgoing messages from now on
+        #[serde(default)]
+        compress: bool,
+        /// End the game as a timeout once it reaches this step
+        #[serde(default)]
+        max_steps: Option<u64>,
+        /// Steps to run before replying, to start mid-game
+        #[serde(default)]
+        start_step: u32,
+    },
+    /// Create `count` sessions from one config, with consecutive maze seeds
+    /// starting at `config.maze_seed`
+    CreateSessions {
+        #[cfg_attr(feature = "schema", schemars(with = "GameConfigSnapshot"))]
+        config: GameConfig,
+        count: u32,
+        #[serde(default = "default_dt")]
+        dt: f32,
+        #[serde(default)]
+        max_steps: Option<u64>,
+    },
     StepSimulation { session_id: String, steps: u32 },
+    /// Like `StepSimulation`, but reply with a snapshot after each step, or
+    /// after evenly sized groups of steps when there are more than `max_frames`
+    StepSimulationTrace { session_id: String, steps: u32, max_frames: u32 },
+    /// Advance a single step and explain what each zombie decided
+    StepDebug { session_id: String },
+    /// Step until the game ends, or at most `max_steps` steps
+    RunToEnd { session_id: String, max_steps: u32 },
+    /// Advance every session on the server by the same number of steps;
+    /// only allowed on servers that require authentication
+    GlobalStep { steps: u32 },
+    /// Seed every session created from now on from `base_seed` and a counter
+    /// instead of its config; only allowed on servers that require
+    /// authentication
+    SetGlobalSeed { base_seed: u64 },
+    /// Advance by simulated seconds; leftover time smaller than one step is
+    /// carried over, so `step` in the reply only counts whole steps
+    AdvanceTime { session_id: String, seconds: f32 },
     GetState { session_id: String },
+    /// Everything the server knows about a session in one message, for bug
+    /// reports
+    DumpState { session_id: String },
+    /// Counts and measures of the session's maze, for judging its difficulty
+    GetMazeStats { session_id: String },
+    /// The maze and agents as text, for logs and bug reports
+    ExportMazeAscii { session_id: String },
+    /// The current state as a flat vector of numbers, for RL clients
+    GetObservation { session_id: String },
+    /// Agent positions `alpha` of the way from the previous step to the
+    /// current one, for rendering between steps
+    GetInterpolated { session_id: String, alpha: f32 },
+    /// Remove a zombie from the game; later zombies shift down one index
+    DespawnZombie { session_id: String, zombie_index: usize },
+    /// Re-fetch the maze, e.g. after reconnecting
+    GetMaze { session_id: String },
+    /// Re-fetch the settings the session was created with
+    GetConfig { session_id: String },
+    /// Receive every new snapshot of a session, whoever advances it
+    SubscribeSession { session_id: String },
+    /// Take over a session whose connection dropped, proving ownership with
+    /// the `reconnect_token` from its `SessionCreated`
+    Reattach { session_id: String, reconnect_token: String },
+    /// Restart the game from the session's original config
+    ResetSession { session_id: String },
+    /// Start a new session that continues independently from where this
+    /// one is now
+    ForkSession { session_id: String },
+    /// Freeze the session; step requests are rejected until resumed
+    PauseSession { session_id: String },
+    ResumeSession { session_id: String },
+    /// Pause the session automatically when a zombie starts pursuing
+    SetBreakOnDetection { session_id: String, enabled: bool },
+    /// Start stepping the session server-side, one step per tick
+    StartSimulation { session_id: String, tick_rate_hz: u32 },
+    /// `StartSimulation`, with this connection's state updates for the session
+    /// sent as binary position frames instead
+    StreamPositions { session_id: String, tick_rate_hz: u32 },
+    /// Stop a running auto-step loop (the session is kept)
+    StopSimulation { session_id: String },
+    /// Choose how the auto-step loop keeps time
+    SetStepMode { session_id: String, mode: StepMode },
+    /// Report a reward in the session's snapshots, or stop with `None`
+    SetRewardShaping {
+        session_id: String,
+        #[serde(default)]
+        config: Option<RewardShaping>,
+    },
     CloseSession { session_id: String },
+    /// Page through active sessions by id (operator tooling, off by default)
+    ListSessions {
+        #[serde(default)]
+        offset: usize,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    /// Keep a snapshot of every step from now until `StopRecording`
+    StartRecording { session_id: String },
+    StopRecording { session_id: String },
+    /// Write the session to the server's save directory so it's reloaded
+    /// when the server restarts
+    SaveSession { session_id: String },
+    /// Whether walls block the straight line between two points
+    CheckVisibility { session_id: String, from: [f32; 2], to: [f32; 2] },
+    /// Shortest walkable route between two world positions in a session's maze
+    QueryPath { session_id: String, from: [f32; 2], to: [f32; 2] },
+    /// Rerun a game from scratch on a throwaway state and check the fugitive
+    /// ends up where a recorded run says it did
+    VerifyReplay {
+        seed: u64,
+        #[cfg_attr(feature = "schema", schemars(with = "GameConfigSnapshot"))]
+        config: GameConfig,
+        #[serde(default = "default_dt")]
+        dt: f32,
+        expected_final_step: u64,
+        expected_fugitive_pos: [f32; 2],
+        #[serde(default = "default_tolerance")]
+        tolerance: f32,
+    },
+    /// Time `steps` steps of a throwaway game, for tracking engine
+    /// performance; only allowed on servers that require authentication
+    Benchmark {
+        #[cfg_attr(feature = "schema", schemars(with = "GameConfigSnapshot"))]
+        config: GameConfig,
+        steps: u64,
+        #[serde(default = "default_dt")]
+        dt: f32,
+    },
+}
+
+/// ~60 FPS
+fn default_dt() -> f32 {
+    0.016
+}
+
+fn default_tolerance() -> f32 {
+    0.001
 }
 
 /// Server → Client messages
 #[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ServerMessage {
+    ServerInfo {
+        protocol_version: u32,
+        crate_version: String,
+        /// Optional capabilities this server has, e.g. `"msgpack"` or
+        /// `"delta_updates"`
+        features: Vec<String>,
+    },
+    Authenticated,
+    #[cfg(feature = "schema")]
+    Schema {
+        /// `client_message`, `server_message` and `game_state_snapshot`
+        /// schemas
+        schema: serde_json::Value,
+    },
     SessionCreated {
         session_id: String,
+        /// Seed the maze was generated from; reuse it to reproduce the layout
+        seed: u64,
+        /// The config the game actually runs with
+        resolved_config: GameConfigSnapshot,
         initial_state: GameStateSnapshot,
-        maze_grid: Vec<Vec<String>>,  // Send full maze grid only once
+        maze_grid: MazeGrid,  // Send full maze grid only once
+        /// Secret for `Reattach`, only given when the session is created
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reconnect_token: Option<String>,
+    },
+    /// Reply to `CreateSessions`, without mazes or states to keep it small
+    SessionsCreated {
+        session_ids: Vec<String>,
+        /// Why fewer sessions than asked for were created, if they were
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
     },
     StateUpdate {
         session_id: String,
         state: GameStateSnapshot,
     },
+    /// Snapshots taken along a `StepSimulationTrace`, oldest first
+    StateTrace {
+        session_id: String,
+        frames: Vec<GameStateSnapshot>,
+    },
+    /// Reply to `GlobalStep`: `[session_id, state]` pairs sorted by id
+    GlobalStateUpdate {
+        updates: Vec<(String, GameStateSnapshot)>,
+    },
+    GlobalSeedSet {
+        base_seed: u64,
+    },
+    /// Reply to `RunToEnd`
+    RunFinished {
+        session_id: String,
+        state: GameStateSnapshot,
+        /// Whether the game ended, rather than running out of steps
+        terminated: bool,
+    },
+    /// Reply to `StepDebug`: the state after the step and one entry per zombie
+    DebugStep {
+        session_id: String,
+        frame: GameStateSnapshot,
+        reasons: Vec<ZombieDecision>,
+    },
+    /// Changes since the last snapshot sent for this session on this connection
+    StateDelta {
+        session_id: String,
+        step: u64,
+        #[cfg_attr(feature = "schema", schemars(with = "String"))]
+        status: GameStatus,
+        pursuing_count: usize,
+        closest_approach: Option<f32>,
+        catch_imminent: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reward: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        done: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        end_reason: Option<EndReason>,
+        paused: bool,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        events: Vec<GameEvent>,
+        /// Number of zombies after applying the delta
+        zombie_count: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fugitive: Option<AgentDelta>,
+        /// Only zombies with at least one changed field
+        zombies: Vec<ZombieDelta>,
+        /// Ids of zombies that were in the last snapshot but are gone now
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        removed_ids: Vec<u64>,
+    },
+    SimulationStarted {
+        session_id: String,
+        tick_rate_hz: u32,
+    },
+    SimulationStopped {
+        session_id: String,
+    },
+    StepModeSet {
+        session_id: String,
+        mode: StepMode,
+    },
+    MazeStats {
+        session_id: String,
+        walkable_cells: usize,
+        wall_cells: usize,
+        /// Length of the shortest route from the start to the exit, `None`
+        /// if walls separate them
+        shortest_path_len: Option<f32>,
+        /// Walkable cells with a single walkable neighbour
+        dead_ends: usize,
+        /// Average number of walkable neighbours of a walkable cell
+        branching_factor: f32,
+    },
+    MazeAscii {
+        session_id: String,
+        /// One line per row, top row first
+        text: String,
+    },
+    Interpolated {
+        session_id: String,
+        /// The current step; positions are between it and the one before
+        step: u64,
+        /// `alpha` as used, after clamping to [0, 1]
+        alpha: f32,
+        fugitive: [f32; 2],
+        zombies: Vec<ZombiePosition>,
+    },
+    RewardShapingSet {
+        session_id: String,
+        config: Option<RewardShaping>,
+    },
+    Observation {
+        session_id: String,
+        values: Vec<f32>,
+        /// Name of each entry in `values`; the same for the whole game
+        layout: Vec<String>,
+    },
+    MazeData {
+        session_id: String,
+        maze_grid: MazeGrid,
+        maze_info: MazeInfo,
+    },
+    /// Reply to `GetConfig`: the settings a session was created with
+    ConfigData {
+        session_id: String,
+        config: GameConfigSnapshot,
+        dt: f32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_steps: Option<u64>,
+    },
+    /// Current state and maze for a newly subscribed spectator
+    Subscribed {
+        session_id: String,
+        state: GameStateSnapshot,
+        maze_grid: MazeGrid,
+    },
+    /// Reply to `DumpState`, taken under the session's lock so every part is
+    /// from the same step
+    StateDump {
+        session_id: String,
+        /// Server build the dump came from; replays only match on the same
+        /// engine
+        crate_version: String,
+        seed: u64,
+        config: GameConfigSnapshot,
+        dt: f32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_steps: Option<u64>,
+        /// Simulated seconds from `AdvanceTime` not yet stepped
+        time_accumulator: f32,
+        step_mode: StepMode,
+        maze_encoding: MazeEncoding,
+        coordinates: CoordinateConvention,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reward_shaping: Option<RewardShaping>,
+        /// Whether the session pauses as soon as a zombie switches to pursuit
+        break_on_detection: bool,
+        /// Whether a recording is running, and how many frames it holds
+        recording: bool,
+        recorded_frames: usize,
+        /// Steps the fugitive has to stay put for to be reported stuck, 0 for
+        /// never
+        stuck_steps: u32,
+        /// How far it may move over those steps and still count as stuck, in
+        /// cells
+        stuck_distance: f32,
+        /// Whether the fugitive is reported stuck and hasn't moved since
+        stuck: bool,
+        /// Fugitive positions the stuck check is looking back over, oldest
+        /// first
+        fugitive_history: Vec<[f32; 2]>,
+        state: GameStateSnapshot,
+        maze_grid: MazeGrid,
+    },
+    /// The session now belongs to this connection
+    Reattached {
+        session_id: String,
+        state: GameStateSnapshot,
+        maze_grid: MazeGrid,
+    },
+    SessionClosed {
+        session_id: String,
+        /// Sessions left on the server after this one was removed
+        remaining_sessions: usize,
+    },
+    SessionList {
+        sessions: Vec<SessionSummary>,
+        /// Sessions on the server, across every page
+        total: usize,
+        /// Position of the first of `sessions` among all of them
+        offset: usize,
+    },
+    SessionSaved {
+        session_id: String,
+        /// File the session was written to, on the server
+        path: String,
+    },
+    RecordingStarted {
+        session_id: String,
+        step: u64,
+    },
+    /// Everything needed to play back a recording, or to rerun it with
+    /// `CreateSession`
+    Replay {
+        session_id: String,
+        seed: u64,
+        config: GameConfigSnapshot,
+        dt: f32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_steps: Option<u64>,
+        /// The state when recording started, then one per step
+        frames: Vec<GameStateSnapshot>,
+        /// Whether frames were dropped after the recording filled up
+        truncated: bool,
+    },
+    PathResult {
+        session_id: String,
+        /// `None` when walls separate the two points
+        path: Option<Vec<[f32; 2]>>,
+        /// Length of `path` in world units, 0 when there is none
+        length: f32,
+    },
+    Visibility {
+        session_id: String,
+        visible: bool,
+        /// Where the line first enters a wall, when it's blocked
+        blocked_at: Option<[f32; 2]>,
+    },
+    /// Outcome of `VerifyReplay`
+    ReplayVerified {
+        /// Whether the rerun reached `expected_final_step` with the fugitive
+        /// within `tolerance` of `expected_fugitive_pos`
+        matches: bool,
+        final_step: u64,
+        fugitive_pos: [f32; 2],
+        /// Distance from `expected_fugitive_pos`
+        distance: f32,
+    },
+    /// Reply to `Benchmark`
+    BenchResult {
+        /// Steps actually run, fewer than asked if the game ended first
+        steps: u64,
+        /// Wall-clock time spent stepping, not counting maze generation
+        total_ms: f64,
+        steps_per_second: f64,
+        #[cfg_attr(feature = "schema", schemars(with = "String"))]
+        final_status: GameStatus,
+    },
+    /// Sent to every connection right before the server closes it
+    ServerShutdown {
+        reason: String,
+    },
     Error {
         message: String,
-        code: String,
+        code: ErrorCode,
     },
 }
 
+/// Machine-readable reason carried by `ServerMessage::Error`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The message couldn't be decoded
+    ParseError,
+    SessionNotFound,
+    /// The simulation panicked mid-step; a session left like that can no
+    /// longer be used
+    SessionPoisoned,
+    SessionPaused,
+    InvalidTickRate,
+    InvalidDuration,
+    InvalidDt,
+    InvalidZombieIndex,
+    /// A position outside the maze or inside a wall
+    InvalidPosition,
+    /// More steps than the server runs for a single request
+    StepsTooLarge,
+    /// `StartRecording` on a session that is already recording
+    RecordingActive,
+    /// `StopRecording` on a session that isn't recording
+    NotRecording,
+    /// The `GameConfig` in `create_session` was rejected
+    ConfigInvalid,
+    /// Disabled by the server's configuration
+    Forbidden,
+    /// Wrong token, or a message sent before authenticating. The server
+    /// closes the connection after sending it.
+    Unauthorized,
+    /// `Reattach` with a token that isn't the session's reconnect token
+    InvalidReconnectToken,
+    /// The server or this connection already has as many sessions as allowed
+    CapacityReached,
+    /// The connection sent more messages per second than the server allows
+    RateLimited,
+    /// `Hello` asked for a protocol version the server doesn't speak. The
+    /// server closes the connection after sending it.
+    ProtocolVersionUnsupported,
+    /// `SaveSession` couldn't write the save file
+    SaveFailed,
+    /// `ForkSession` couldn't rebuild the session by replaying it
+    ForkFailed,
+    /// The maze in `create_session` has too few open cells for its zombies
+    TooManyAgents,
+    /// A well-formed message whose `type` this server doesn't know. The
+    /// error's `message` is the type.
+    UnknownMessageType,
+    /// The reply couldn't be encoded, so this error was sent in its place
+    EncodeFailed,
+}
+
+/// A server message as sent on the wire, with per-connection delivery metadata.
+///
+/// Only state-bearing messages (`session_created`, `state_update`,
+/// `state_delta`, `state_trace`) are stamped for now.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Envelope {
+    #[serde(flatten)]
+    pub message: ServerMessage,
+    /// Increases by one with every stamped message on this connection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// Unix epoch milliseconds when the message was sent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_time_ms: Option<u64>,
+    /// The `request_id` of the client message this replies to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Lightweight description of a session for `SessionList`
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SessionSummary {
+    pub session_id: String,
+    pub current_step: u64,
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    pub status: GameStatus,
+    pub zombie_count: usize,
+    pub paused: bool,
+}
+
+/// What a zombie saw and decided during a `StepDebug` step
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ZombieDecision {
+    pub zombie_id: u64,
+    /// Whether the zombie spotted the fugitive this step, i.e. its last
+    /// sighting moved
+    pub saw_fugitive: bool,
+    /// Where the zombie last saw the fugitive, if ever
+    pub sighting_pos: Option<[f32; 2]>,
+    /// State before the step
+    pub previous_state: String,
+    /// State the zombie ended the step in
+    pub chose_state: String,
+}
+
 /// Serializable game state snapshot
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct GameStateSnapshot {
     pub step: u64,
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
     pub status: GameStatus,
+    /// Zombies currently in the `pursuit` state
+    pub pursuing_count: usize,
+    /// Smallest distance any zombie has come to the fugitive this game, or
+    /// `None` without zombies
+    pub closest_approach: Option<f32>,
+    /// A zombie is within 1.5 times `capture_radius` of the fugitive
+    pub catch_imminent: bool,
+    /// Reward earned over the steps that produced this snapshot, once the
+    /// session has reward shaping
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reward: Option<f32>,
+    /// Whether the game is over, alongside `reward`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub done: Option<bool>,
+    /// Why the game ended, once `status` is no longer running
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_reason: Option<EndReason>,
+    pub paused: bool,
+    /// Simulated seconds per step
+    pub dt: f32,
+    /// Step at which the game times out, if limited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_steps: Option<u64>,
+    /// Notable things that happened during the steps that produced this snapshot
+    pub events: Vec<GameEvent>,
     pub fugitive: AgentSnapshot,
     pub zombies: Vec<AgentSnapshot>,
     pub maze_info: MazeInfo,
 }
 
+/// How a finished game ended
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum EndReason {
+    /// The fugitive reached the exit
+    Escaped,
+    /// A zombie got the fugitive; `zombie_id` is the one closest to it at the
+    /// time, if any zombies were left
+    Caught { zombie_id: Option<u64> },
+    /// The session's `max_steps` ran out first. `status` stays `running`,
+    /// since the engine has no draw state, but the game no longer advances.
+    Timeout,
+}
+
+/// Something notable that happened during a step
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum GameEvent {
+    /// A zombie's FSM switched state (e.g. "wander" to "pursuit")
+    ZombieStateChanged {
+        zombie_index: usize,
+        zombie_id: u64,
+        from: String,
+        to: String,
+        step: u64,
+    },
+    /// The fugitive has barely moved for `steps` steps while the game is
+    /// running. Sent once, when it starts; it's sent again if the fugitive
+    /// gets moving and then stalls again.
+    FugitiveStuck {
+        position: [f32; 2],
+        steps: u32,
+        step: u64,
+    },
+}
+
 /// Serializable agent snapshot with type discrimination
 ///
 /// Uses a tagged enum to differentiate between fugitive and zombie agents,
 /// allowing zombie-specific visualization data (vision cones, paths, etc.)
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(tag = "agent_type", rename_all = "snake_case")]
 pub enum AgentSnapshot {
     /// Fugitive agent snapshot
@@ -54,15 +662,35 @@ pub enum AgentSnapshot {
         /// Optional: Current path being followed (A* waypoints)
         #[serde(skip_serializing_if = "Option::is_none")]
         current_path: Option<Vec<[f32; 2]>>,
+        /// Where the fugitive is heading: the end of `current_path`, or the
+        /// exit while it has no path
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target: Option<[f32; 2]>,
+        /// Straight-line distance to the closest zombie, `None` when there
+        /// are no zombies
+        nearest_zombie_distance: Option<f32>,
+        /// Share of the route from the start to the exit covered so far,
+        /// from 0.0 to 1.0
+        progress_to_exit: f32,
         vision_angle: f32,
         vision_range: f32,
+        /// Set when any number in this snapshot wasn't finite; those values
+        /// are sent as 0.0 instead
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
     },
     /// Zombie agent snapshot with debug visualization data
     Zombie {
+        /// Stable for the zombie's lifetime; ids are never reused within a
+        /// game, and 0 is reserved for the fugitive
+        id: u64,
         position: [f32; 2],
         velocity: [f32; 2],
         /// Current FSM state ("wander" or "pursuit")
         state: String,
+        /// 1.0 while pursuing, fading to 0.0 over the simulated seconds after
+        /// it gives up; 0.0 if it has never pursued
+        alertness: f32,
         /// Vision range in world units
         vision_range: f32,
         /// Vision cone half-angle in radians
@@ -73,15 +701,390 @@ pub enum AgentSnapshot {
         /// Current Dijkstra path being followed
         #[serde(skip_serializing_if = "Option::is_none")]
         current_path: Option<Vec<[f32; 2]>>,
+        /// Same as the fugitive's
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
     },
 }
 
+impl AgentSnapshot {
+    pub fn position(&self) -> [f32; 2] {
+        match self {
+            AgentSnapshot::Fugitive { position, .. } | AgentSnapshot::Zombie { position, .. } => {
+                *position
+            }
+        }
+    }
+
+    pub fn velocity(&self) -> [f32; 2] {
+        match self {
+            AgentSnapshot::Fugitive { velocity, .. } | AgentSnapshot::Zombie { velocity, .. } => {
+                *velocity
+            }
+        }
+    }
+
+    pub fn vision_range(&self) -> f32 {
+        match self {
+            AgentSnapshot::Fugitive { vision_range, .. }
+            | AgentSnapshot::Zombie { vision_range, .. } => *vision_range,
+        }
+    }
+
+    pub fn vision_angle(&self) -> f32 {
+        match self {
+            AgentSnapshot::Fugitive { vision_angle, .. }
+            | AgentSnapshot::Zombie { vision_angle, .. } => *vision_angle,
+        }
+    }
+
+    pub fn current_path(&self) -> Option<&Vec<[f32; 2]>> {
+        match self {
+            AgentSnapshot::Fugitive { current_path, .. }
+            | AgentSnapshot::Zombie { current_path, .. } => current_path.as_ref(),
+        }
+    }
+
+    /// FSM state, zombies only
+    pub fn state(&self) -> Option<&str> {
+        match self {
+            AgentSnapshot::Fugitive { .. } => None,
+            AgentSnapshot::Zombie { state, .. } => Some(state),
+        }
+    }
+
+    /// Stable id, zombies only
+    pub fn id(&self) -> Option<u64> {
+        match self {
+            AgentSnapshot::Fugitive { .. } => None,
+            AgentSnapshot::Zombie { id, .. } => Some(*id),
+        }
+    }
+
+    /// What was wrong with the engine's values for this agent, if anything
+    pub fn error(&self) -> Option<&str> {
+        match self {
+            AgentSnapshot::Fugitive { error, .. } | AgentSnapshot::Zombie { error, .. } => {
+                error.as_deref()
+            }
+        }
+    }
+
+    /// How recently it pursued the fugitive, zombies only
+    pub fn alertness(&self) -> Option<f32> {
+        match self {
+            AgentSnapshot::Fugitive { .. } => None,
+            AgentSnapshot::Zombie { alertness, .. } => Some(*alertness),
+        }
+    }
+
+    /// Last known fugitive position, zombies only
+    pub fn last_seen_position(&self) -> Option<[f32; 2]> {
+        match self {
+            AgentSnapshot::Fugitive { .. } => None,
+            AgentSnapshot::Zombie {
+                last_seen_position, ..
+            } => *last_seen_position,
+        }
+    }
+
+    pub fn nearest_zombie_distance(&self) -> Option<f32> {
+        match self {
+            AgentSnapshot::Fugitive {
+                nearest_zombie_distance,
+                ..
+            } => *nearest_zombie_distance,
+            AgentSnapshot::Zombie { .. } => None,
+        }
+    }
+
+    pub fn target(&self) -> Option<[f32; 2]> {
+        match self {
+            AgentSnapshot::Fugitive { target, .. } => *target,
+            AgentSnapshot::Zombie { .. } => None,
+        }
+    }
+
+    pub fn progress_to_exit(&self) -> Option<f32> {
+        match self {
+            AgentSnapshot::Fugitive {
+                progress_to_exit, ..
+            } => Some(*progress_to_exit),
+            AgentSnapshot::Zombie { .. } => None,
+        }
+    }
+}
+
+/// Changed fields of an agent; absent fields are unchanged.
+///
+/// For the optional fields, `null` means the value was cleared.
+#[derive(Debug, Clone, Default, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct AgentDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<[f32; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub velocity: Option<[f32; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alertness: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vision_range: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vision_angle: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen_position: Option<Option<[f32; 2]>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_path: Option<Option<Vec<[f32; 2]>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<[f32; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nearest_zombie_distance: Option<Option<f32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress_to_exit: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<Option<String>>,
+}
+
+/// Zombie delta, with both its stable id and its current index in
+/// `GameStateSnapshot::zombies`
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ZombieDelta {
+    pub index: usize,
+    pub id: u64,
+    #[serde(flatten)]
+    pub changes: AgentDelta,
+}
+
+/// Serializable copy of a session's `GameConfig`, in the same shape
+/// `CreateSession` accepts
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct GameConfigSnapshot {
+    pub maze_width: usize,
+    pub maze_height: usize,
+    pub zombie_count: usize,
+    pub fugitive_speed: f32,
+    pub zombie_speed: f32,
+    pub capture_radius: f32,
+    pub maze_seed: u64,
+    pub cell_size: f32,
+    pub braid_probability: f32,
+}
+
+impl From<&GameConfig> for GameConfigSnapshot {
+    fn from(config: &GameConfig) -> Self {
+        GameConfigSnapshot {
+            maze_width: config.maze_width,
+            maze_height: config.maze_height,
+            zombie_count: config.zombie_count,
+            fugitive_speed: config.fugitive_speed,
+            zombie_speed: config.zombie_speed,
+            capture_radius: config.capture_radius,
+            maze_seed: config.maze_seed,
+            cell_size: config.cell_size,
+            braid_probability: config.braid_probability,
+        }
+    }
+}
+
 /// Maze information for clients
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct MazeInfo {
     pub width: usize,
     pub height: usize,
     pub cell_size: f32,
+    /// In the session's `CoordinateConvention`, like every reported position
     pub start_position: [f32; 2],
     pub exit_position: [f32; 2],
+    /// `[x, y]` index of the cell `start_position` is in
+    pub start_cell: [usize; 2],
+    /// `[x, y]` index of the cell `exit_position` is in
+    pub exit_cell: [usize; 2],
+}
+
+/// Encoding of server messages on a connection
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum WireFormat {
+    /// JSON in text frames (the default)
+    Json,
+    /// MessagePack in binary frames, with named fields
+    Msgpack,
+    /// CBOR in binary frames
+    Cbor,
+}
+
+/// Weights of the reward reported in snapshots. Every field defaults, so
+/// `{}` gives the defaults.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default)]
+pub struct RewardShaping {
+    /// Multiplies the change in `progress_to_exit`, from 0 to 1 over the
+    /// whole route
+    pub progress_weight: f32,
+    /// Subtracted per step for each zombie in pursuit
+    pub seen_penalty: f32,
+    /// Added on the step the fugitive escapes
+    pub escape_bonus: f32,
+    /// Subtracted on the step the fugitive is caught
+    pub capture_penalty: f32,
+}
+
+impl Default for RewardShaping {
+    fn default() -> Self {
+        RewardShaping {
+            progress_weight: 1.0,
+            seen_penalty: 0.01,
+            escape_bonus: 1.0,
+            capture_penalty: 1.0,
+        }
+    }
+}
+
+impl RewardShaping {
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in [
+            ("progress_weight", self.progress_weight),
+            ("seen_penalty", self.seen_penalty),
+            ("escape_bonus", self.escape_bonus),
+            ("capture_penalty", self.capture_penalty),
+        ] {
+            if !value.is_finite() {
+                return Err(format!("{} must be a finite number, got {}", name, value));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How a session's auto-step loop advances the game
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum StepMode {
+    /// Exactly one step per tick, however late the tick fires, so the game
+    /// only depends on the number of ticks
+    #[default]
+    Strict,
+    /// As many steps as the wall-clock time since the last tick covers, so
+    /// simulated time keeps up with real time when ticks run late
+    WallClock,
+}
+
+/// Where positions reported for a session put a cell, chosen at
+/// `CreateSession`. Positions sent by the client, as in `QueryPath`, use the
+/// same convention.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum CoordinateConvention {
+    /// Cell `(x, y)` spans `x * cell_size` to `(x + 1) * cell_size`, so an
+    /// agent in the middle of it is at `(x + 0.5) * cell_size`, as the engine
+    /// has it
+    #[default]
+    Center,
+    /// Everything shifted back half a cell, so an agent in the middle of cell
+    /// `(x, y)` is at `x * cell_size`, the corner a renderer draws the cell
+    /// from
+    Corner,
+}
+
+impl CoordinateConvention {
+    /// Engine `position` as reported under this convention
+    pub fn report(self, [x, y]: [f32; 2], cell_size: f32) -> [f32; 2] {
+        match self {
+            CoordinateConvention::Center => [x, y],
+            CoordinateConvention::Corner => [x - cell_size / 2.0, y - cell_size / 2.0],
+        }
+    }
+
+    /// Client `position` under this convention, back in engine coordinates
+    pub fn world(self, [x, y]: [f32; 2], cell_size: f32) -> [f32; 2] {
+        match self {
+            CoordinateConvention::Center => [x, y],
+            CoordinateConvention::Corner => [x + cell_size / 2.0, y + cell_size / 2.0],
+        }
+    }
+}
+
+/// How the maze grid is encoded on the wire, chosen at `CreateSession`
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum MazeEncoding {
+    /// Rows of `"walkable"` / `"wall"` strings (the original format)
+    #[default]
+    Strings,
+    /// Row-major cells, 0 = wall, 1 = walkable
+    Flat,
+    /// Row-major `[cell, count]` runs using the same cell values as `Flat`
+    RunLength,
+    /// One bit per cell, 1 = walkable, packed row-major into bytes
+    /// starting from each byte's least significant bit
+    Bitset,
+    /// Wall outlines as merged line segments, for vector rendering
+    Segments,
+}
+
+/// Maze grid in the encoding requested by the client
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(untagged)]
+pub enum MazeGrid {
+    Strings(Vec<Vec<String>>),
+    Flat {
+        width: usize,
+        height: usize,
+        cells: Vec<u8>,
+    },
+    RunLength {
+        width: usize,
+        height: usize,
+        runs: Vec<(u8, u32)>,
+    },
+    Bitset {
+        width: usize,
+        height: usize,
+        /// Base64 in JSON, raw bytes in MessagePack and CBOR
+        #[serde(serialize_with = "serialize_bits")]
+        #[cfg_attr(feature = "schema", schemars(with = "String"))]
+        bits: Vec<u8>,
+    },
+    Segments {
+        width: usize,
+        height: usize,
+        segments: Vec<WallSegment>,
+    },
+}
+
+/// Where a zombie is, identified by its stable id
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ZombiePosition {
+    pub id: u64,
+    pub position: [f32; 2],
+}
+
+/// A straight piece of wall outline, in world coordinates
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct WallSegment {
+    pub start: [f32; 2],
+    pub end: [f32; 2],
+}
+
+/// Base64 in text formats like JSON, plain bytes in binary ones
+fn serialize_bits<S: Serializer>(bits: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+        serializer.serialize_str(&BASE64_STANDARD.encode(bits))
+    } else {
+        serializer.serialize_bytes(bits)
+    }
 }
@@ -1,9 +1,39 @@
 //! WebSocket protocol definitions for the zombie escape game server
 //!
-//! This module defines the JSON message format for client-server communication.
+//! This module defines the JSON message format for client-server communication,
+//! and the codecs that carry it over other encodings.
 
+pub mod codec;
+pub mod compressed_frame;
 pub mod messages;
+pub mod position_frame;
 
 pub use messages::{
-    ClientMessage, ServerMessage, GameStateSnapshot, AgentSnapshot, MazeInfo,
+    ClientMessage, ServerMessage, GameStateSnapshot, AgentSnapshot, MazeInfo, MazeEncoding,
+    MazeGrid, AgentDelta, ZombieDelta, SessionSummary, ZombieDecision, GameEvent, WallSegment,
+    StepMode, RewardShaping, ZombiePosition, WireFormat, Envelope, ErrorCode, EndReason,
+    GameConfigSnapshot, CoordinateConvention,
 };
+pub use codec::{Codec, DecodeError, decode_binary};
+
+/// Bumped whenever a change to the messages would break existing clients
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// JSON Schema for the messages clients send and receive, and the game
+/// state they carry
+#[cfg(feature = "schema")]
+pub fn json_schema() -> serde_json::Value {
+    serde_json::json!({
+        "client_message": schemars::schema_for!(ClientMessage),
+        "server_message": schemars::schema_for!(ServerMessage),
+        "game_state_snapshot": schemars::schema_for!(GameStateSnapshot),
+    })
+}
+
+/// Oldest protocol version this server still speaks
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// WebSocket subprotocol naming a protocol version, e.g. `zombie-escape.v1`
+pub fn subprotocol(version: u32) -> String {
+    format!("zombie-escape.v{}", version)
+}
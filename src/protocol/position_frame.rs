@@ -0,0 +1,62 @@
+//! Compact binary frames carrying only agent positions
+//!
+//! Layout, all numbers little-endian:
+//!
+//! | bytes | field                                              |
+//! |-------|----------------------------------------------------|
+//! | 1     | `POSITION_FRAME_TAG`                               |
+//! | 8     | step, `u64`                                        |
+//! | 1     | status, see `status_byte`                          |
+//! | 8     | fugitive x, y, `f32` each                          |
+//! | 4     | zombie count, `u32`                                |
+//! | 17 ea | zombie id `u64`, x `f32`, y `f32`, state `u8`      |
+
+use super::messages::{AgentSnapshot, GameStateSnapshot};
+use zombie_scape::GameStatus;
+
+/// First byte of every position frame. MessagePack and CBOR server messages
+/// are maps and never start with it, so clients can tell the frames apart.
+pub const POSITION_FRAME_TAG: u8 = 0x50;
+
+impl GameStateSnapshot {
+    pub fn to_position_frame(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(22 + 17 * self.zombies.len());
+
+        frame.push(POSITION_FRAME_TAG);
+        frame.extend_from_slice(&self.step.to_le_bytes());
+        frame.push(status_byte(self.status));
+        push_position(&mut frame, self.fugitive.position());
+
+        frame.extend_from_slice(&(self.zombies.len() as u32).to_le_bytes());
+        for zombie in &self.zombies {
+            frame.extend_from_slice(&zombie.id().unwrap_or_default().to_le_bytes());
+            push_position(&mut frame, zombie.position());
+            frame.push(zombie_state_byte(zombie));
+        }
+
+        frame
+    }
+}
+
+/// 0 = running, 1 = fugitive won, 2 = zombies captured
+fn status_byte(status: GameStatus) -> u8 {
+    match status {
+        GameStatus::Running => 0,
+        GameStatus::FugitiveWon => 1,
+        GameStatus::ZombiesCaptured => 2,
+    }
+}
+
+/// 0 = wander, 1 = pursuit, 255 = any other state
+fn zombie_state_byte(zombie: &AgentSnapshot) -> u8 {
+    match zombie.state().map(str::to_ascii_lowercase).as_deref() {
+        Some("wander") => 0,
+        Some("pursuit") => 1,
+        _ => u8::MAX,
+    }
+}
+
+fn push_position(frame: &mut Vec<u8>, [x, y]: [f32; 2]) {
+    frame.extend_from_slice(&x.to_le_bytes());
+    frame.extend_from_slice(&y.to_le_bytes());
+}
@@ -0,0 +1,70 @@
+//! Token bucket limiting how fast a connection may send messages
+
+use tokio::time::Instant;
+
+pub struct TokenBucket {
+    /// Tokens gained per second, also the most that can be saved up
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket allowing `per_second` messages a second on average, and
+    /// bursts of up to a second's worth
+    pub fn new(per_second: u32) -> Self {
+        TokenBucket {
+            rate: per_second as f64,
+            tokens: per_second as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Spend a token if one is available
+    pub fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn allows_a_burst_of_one_seconds_worth() {
+        let mut bucket = TokenBucket::new(3);
+
+        assert!((0..3).all(|_| bucket.try_take()));
+        assert!(!bucket.try_take());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refills_at_the_rate() {
+        let mut bucket = TokenBucket::new(10);
+        while bucket.try_take() {}
+
+        tokio::time::advance(Duration::from_millis(250)).await;
+        assert!((0..2).all(|_| bucket.try_take()));
+        assert!(!bucket.try_take());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn saves_up_at_most_one_seconds_worth() {
+        let mut bucket = TokenBucket::new(2);
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!((0..2).all(|_| bucket.try_take()));
+        assert!(!bucket.try_take());
+    }
+}
@@ -0,0 +1,159 @@
+//! Server-wide maze seeds for reproducible runs
+//!
+//! Once a base seed is set, every new session takes the next seed of a
+//! SplitMix64 sequence started from it instead of the seed in its config, so
+//! the same series of `CreateSession` calls builds the same mazes on every
+//! run of the server.
+
+use std::collections::BTreeSet;
+use std::sync::{Mutex, MutexGuard};
+
+/// SplitMix64's increment, so consecutive seeds come out well mixed
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+#[derive(Default)]
+pub struct SeedSequence {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Seed the running sequence started from, `None` until one is set
+    base_seed: Option<u64>,
+    /// How many seeds have been handed out since it was set
+    counter: u64,
+    /// Counters whose session failed to build, handed out again lowest first
+    returned: BTreeSet<u64>,
+    /// Bumped by every `set`, so a late failure can't return its seed to a
+    /// newer sequence
+    generation: u64,
+}
+
+impl SeedSequence {
+    /// Start a new sequence from `base_seed`, even if one was already running
+    pub fn set(&self, base_seed: u64) {
+        let mut state = self.lock();
+        state.base_seed = Some(base_seed);
+        state.counter = 0;
+        state.returned.clear();
+        state.generation += 1;
+    }
+
+    /// Build a session from the next seed, or `None` while no base seed is
+    /// set. The seed is reserved before `build` runs, without holding the
+    /// lock, so sessions can be built in parallel. If `build` fails the seed
+    /// goes back and the next session gets it, so the sequence has no gaps.
+    pub fn with_next<T, E>(&self, build: impl FnOnce(Option<u64>) -> Result<T, E>) -> Result<T, E> {
+        let reserved = {
+            let mut state = self.lock();
+            let generation = state.generation;
+            state.base_seed.map(|base_seed| {
+                let counter = state.returned.pop_first().unwrap_or_else(|| {
+                    state.counter += 1;
+                    state.counter
+                });
+                (base_seed, counter, generation)
+            })
+        };
+        let Some((base_seed, counter, generation)) = reserved else {
+            return build(None);
+        };
+
+        let built = build(Some(derive_seed(base_seed, counter)));
+        if built.is_err() {
+            let mut state = self.lock();
+            if state.generation == generation {
+                state.returned.insert(counter);
+            }
+        }
+        built
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The `counter`th output of SplitMix64 seeded with `base_seed`
+fn derive_seed(base_seed: u64, counter: u64) -> u64 {
+    let mut z = base_seed.wrapping_add(counter.wrapping_mul(GOLDEN_GAMMA));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn take(seeds: &SeedSequence) -> Option<u64> {
+        seeds.with_next(Ok::<_, ()>).unwrap()
+    }
+
+    #[test]
+    fn no_seed_until_one_is_set() {
+        let seeds = SeedSequence::default();
+        assert_eq!(take(&seeds), None);
+        assert_eq!(take(&seeds), None);
+    }
+
+    #[test]
+    fn same_base_seed_gives_same_sequence() {
+        let seeds = SeedSequence::default();
+        seeds.set(42);
+        let first: Vec<_> = (0..3).map(|_| take(&seeds)).collect();
+
+        seeds.set(42);
+        let second: Vec<_> = (0..3).map(|_| take(&seeds)).collect();
+
+        assert_eq!(first, second);
+        assert_eq!(first[0], Some(derive_seed(42, 1)));
+        assert_ne!(first[0], first[1]);
+        assert_ne!(first[1], first[2]);
+    }
+
+    #[test]
+    fn failed_build_keeps_its_seed_for_the_next_session() {
+        let seeds = SeedSequence::default();
+        seeds.set(7);
+
+        let mut offered = None;
+        let failed: Result<(), ()> = seeds.with_next(|seed| {
+            offered = seed;
+            Err(())
+        });
+
+        assert!(failed.is_err());
+        assert_eq!(take(&seeds), offered);
+        assert_eq!(take(&seeds), Some(derive_seed(7, 2)));
+    }
+
+    #[test]
+    fn build_runs_without_the_lock_held() {
+        let seeds = SeedSequence::default();
+        seeds.set(7);
+
+        // Would deadlock if the sequence stayed locked during `build`
+        let (outer, inner) = seeds
+            .with_next(|outer| Ok::<_, ()>((outer, take(&seeds))))
+            .unwrap();
+
+        assert_eq!(outer, Some(derive_seed(7, 1)));
+        assert_eq!(inner, Some(derive_seed(7, 2)));
+    }
+
+    #[test]
+    fn failure_after_a_reset_doesnt_return_its_seed() {
+        let seeds = SeedSequence::default();
+        seeds.set(7);
+
+        let failed: Result<(), ()> = seeds.with_next(|_| {
+            seeds.set(9);
+            Err(())
+        });
+
+        assert!(failed.is_err());
+        assert_eq!(take(&seeds), Some(derive_seed(9, 1)));
+        assert_eq!(take(&seeds), Some(derive_seed(9, 2)));
+    }
+}
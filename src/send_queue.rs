@@ -0,0 +1,184 @@
+//! Outgoing frames for one connection, written out by their own task
+//!
+//! Writing to the socket waits on the client, so a slow reader would hold up
+//! everything else the connection does. Instead the connection pushes frames
+//! here without blocking and a writer task drains them. Once the queue is
+//! full, the oldest droppable frame makes room for the new one: state updates
+//! pushed by tick loops are superseded by the next one anyway. Deltas each
+//! build on the one before, so they're only ever dropped all at once, by a
+//! connection that then resyncs its client with full updates. Other frames
+//! are never dropped.
+
+use futures_util::SinkExt;
+use futures_util::stream::SplitSink;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, error};
+
+type Sink = SplitSink<WebSocketStream<TcpStream>, Message>;
+
+pub struct SendQueue {
+    capacity: usize,
+    inner: Mutex<Inner>,
+    /// Wakes the writer when a frame is pushed or the queue is closed
+    ready: Notify,
+}
+
+struct Inner {
+    frames: VecDeque<Queued>,
+    /// Set once the writer stops, or the connection closes the queue
+    closed: bool,
+}
+
+struct Queued {
+    frame: Message,
+    delivery: Delivery,
+}
+
+/// What may happen to a frame while it waits in a full queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Can make room for a newer frame
+    Droppable,
+    /// A state delta pushed by a tick loop, dropped only by `drop_deltas`
+    Delta,
+    /// Always sent
+    Required,
+}
+
+impl SendQueue {
+    pub fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(SendQueue {
+            capacity,
+            inner: Mutex::new(Inner {
+                frames: VecDeque::new(),
+                closed: false,
+            }),
+            ready: Notify::new(),
+        })
+    }
+
+    /// Queue a frame for sending. Returns `false` once the writer has stopped,
+    /// so nothing more will be sent.
+    pub fn push(&self, frame: Message, delivery: Delivery) -> bool {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if inner.closed {
+            return false;
+        }
+
+        if inner.frames.len() >= self.capacity {
+            let droppable = |queued: &Queued| queued.delivery == Delivery::Droppable;
+            match inner.frames.iter().position(droppable) {
+                Some(oldest) => {
+                    inner.frames.remove(oldest);
+                    debug!(capacity = self.capacity, "Send queue full, dropped a state update");
+                }
+                // Nothing older can go, so a droppable frame is the one to skip
+                None if delivery == Delivery::Droppable => return true,
+                // Past capacity, but only by frames that must arrive
+                None => {}
+            }
+        }
+
+        inner.frames.push_back(Queued { frame, delivery });
+        drop(inner);
+        self.ready.notify_one();
+        true
+    }
+
+    /// Whether the queue holds as many frames as it should
+    pub fn is_full(&self) -> bool {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).frames.len() >= self.capacity
+    }
+
+    /// Remove every queued delta and return how many there were
+    pub fn drop_deltas(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let before = inner.frames.len();
+        inner.frames.retain(|queued| queued.delivery != Delivery::Delta);
+        before - inner.frames.len()
+    }
+
+    /// Stop accepting frames. The writer still sends what's already queued.
+    pub fn close(&self) {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).closed = true;
+        self.ready.notify_one();
+    }
+
+    /// Next frame to write, or `None` once the queue is closed and drained
+    async fn pop(&self) -> Option<Message> {
+        loop {
+            {
+                let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+                if let Some(queued) = inner.frames.pop_front() {
+                    return Some(queued.frame);
+                }
+                if inner.closed {
+                    return None;
+                }
+            }
+            self.ready.notified().await;
+        }
+    }
+}
+
+/// Spawn the task writing `queue`'s frames to the socket. It ends after a
+/// close frame, once the queue is closed and drained, or when a write fails.
+pub fn spawn_writer(mut write: Sink, queue: Arc<SendQueue>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Some(frame) = queue.pop().await {
+            let closing = matches!(frame, Message::Close(_));
+            if let Err(e) = write.send(frame).await {
+                error!(error = %e, "Failed to send message");
+                break;
+            }
+            if closing {
+                break;
+            }
+        }
+
+        queue.close();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(body: &str) -> Message {
+        Message::Text(body.to_string().into())
+    }
+
+    fn queued(queue: &SendQueue) -> Vec<Message> {
+        let inner = queue.inner.lock().unwrap();
+        inner.frames.iter().map(|queued| queued.frame.clone()).collect()
+    }
+
+    #[test]
+    fn full_queue_drops_oldest_droppable_frame() {
+        let queue = SendQueue::new(2);
+        queue.push(text("update 1"), Delivery::Droppable);
+        queue.push(text("reply"), Delivery::Required);
+        queue.push(text("update 2"), Delivery::Droppable);
+
+        assert_eq!(queued(&queue), [text("reply"), text("update 2")]);
+    }
+
+    #[test]
+    fn deltas_outgrow_capacity_until_dropped() {
+        let queue = SendQueue::new(2);
+        queue.push(text("delta 1"), Delivery::Delta);
+        queue.push(text("reply"), Delivery::Required);
+        queue.push(text("delta 2"), Delivery::Delta);
+        assert!(queue.is_full());
+
+        assert_eq!(queue.drop_deltas(), 2);
+        assert_eq!(queued(&queue), [text("reply")]);
+        assert!(!queue.is_full());
+    }
+}
@@ -0,0 +1,61 @@
+//! Line of sight through a session's maze for `CheckVisibility`
+//!
+//! Walks the grid cells a straight line passes through and stops at the
+//! first wall, the same occlusion rule vision cones are drawn against.
+//! Like `pathfinding`, it only uses the grid, not engine internals.
+
+use zombie_scape::Grid2D;
+
+use crate::is_cell_walkable;
+use crate::pathfinding::cell_at;
+
+/// Where the line from `from` to `to` first enters a wall, or `None` if
+/// nothing blocks it. Both points must be inside the grid.
+pub fn first_wall_hit(grid: &Grid2D, from: [f32; 2], to: [f32; 2]) -> Option<[f32; 2]> {
+    let (start, goal) = (cell_at(grid, from)?, cell_at(grid, to)?);
+    if !is_cell_walkable(grid, start.0, start.1) {
+        return Some(from);
+    }
+
+    let cell_size = grid.cell_size();
+    let [dx, dy] = [to[0] - from[0], to[1] - from[1]];
+
+    // Amanatides & Woo: `t_max` is how far along the line, as a fraction of
+    // its length, the next vertical or horizontal cell border is crossed
+    let axis = |from: f32, delta: f32, cell: usize| -> (isize, f32, f32) {
+        if delta > 0.0 {
+            let border = (cell + 1) as f32 * cell_size;
+            (1, (border - from) / delta, cell_size / delta)
+        } else if delta < 0.0 {
+            let border = cell as f32 * cell_size;
+            (-1, (border - from) / delta, -cell_size / delta)
+        } else {
+            (0, f32::INFINITY, f32::INFINITY)
+        }
+    };
+    let (step_x, mut t_max_x, t_delta_x) = axis(from[0], dx, start.0);
+    let (step_y, mut t_max_y, t_delta_y) = axis(from[1], dy, start.1);
+
+    let (mut x, mut y) = (start.0 as isize, start.1 as isize);
+    while (x, y) != (goal.0 as isize, goal.1 as isize) {
+        let t = if t_max_x < t_max_y {
+            x += step_x;
+            t_max_x += t_delta_x;
+            t_max_x - t_delta_x
+        } else {
+            y += step_y;
+            t_max_y += t_delta_y;
+            t_max_y - t_delta_y
+        };
+
+        // Float error can overshoot the goal at the grid's edge
+        if x < 0 || y < 0 || x as usize >= grid.width() || y as usize >= grid.height() || t > 1.0 {
+            break;
+        }
+        if !is_cell_walkable(grid, x as usize, y as usize) {
+            return Some([from[0] + dx * t, from[1] + dy * t]);
+        }
+    }
+
+    None
+}
@@ -0,0 +1,165 @@
+//! Maze outlines as line segments for vector rendering
+//!
+//! Every border between a wall cell and an open cell (or the outside of the
+//! grid) is a wall edge. Edges along the same grid line are merged into the
+//! longest runs possible, so a straight wall of any length is one segment.
+
+use zombie_scape::Grid2D;
+
+use crate::is_cell_walkable;
+use crate::protocol::WallSegment;
+
+pub fn wall_segments(grid: &Grid2D) -> Vec<WallSegment> {
+    outline(grid.width(), grid.height(), grid.cell_size(), |x, y| {
+        !is_cell_walkable(grid, x, y)
+    })
+}
+
+/// Segments around the cells of a `width` x `height` grid for which
+/// `is_wall` holds
+fn outline(
+    width: usize,
+    height: usize,
+    cell_size: f32,
+    is_wall: impl Fn(usize, usize) -> bool,
+) -> Vec<WallSegment> {
+    let wall = |x: isize, y: isize| {
+        x >= 0
+            && y >= 0
+            && (x as usize) < width
+            && (y as usize) < height
+            && is_wall(x as usize, y as usize)
+    };
+
+    let mut segments = Vec::new();
+
+    // Horizontal grid lines, between row y - 1 and row y
+    for y in 0..=height as isize {
+        let edges = (0..width as isize).map(|x| wall(x, y - 1) != wall(x, y));
+        for (start, end) in runs(edges) {
+            segments.push(WallSegment {
+                start: [start as f32 * cell_size, y as f32 * cell_size],
+                end: [end as f32 * cell_size, y as f32 * cell_size],
+            });
+        }
+    }
+
+    // Vertical grid lines, between column x - 1 and column x
+    for x in 0..=width as isize {
+        let edges = (0..height as isize).map(|y| wall(x - 1, y) != wall(x, y));
+        for (start, end) in runs(edges) {
+            segments.push(WallSegment {
+                start: [x as f32 * cell_size, start as f32 * cell_size],
+                end: [x as f32 * cell_size, end as f32 * cell_size],
+            });
+        }
+    }
+
+    segments
+}
+
+/// Half-open `(start, end)` ranges of consecutive `true`s
+fn runs(edges: impl Iterator<Item = bool>) -> Vec<(usize, usize)> {
+    let mut runs = Vec::new();
+    let mut start = None;
+    let mut len = 0;
+
+    for (index, edge) in edges.enumerate() {
+        match (edge, start) {
+            (true, None) => start = Some(index),
+            (false, Some(from)) => {
+                runs.push((from, index));
+                start = None;
+            }
+            _ => {}
+        }
+        len = index + 1;
+    }
+    if let Some(from) = start {
+        runs.push((from, len));
+    }
+
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Outline of a grid drawn as rows of `#` (wall) and `.` (open), with
+    /// unit cells, as `(start, end)` pairs
+    fn outline_of(rows: &[&str]) -> Vec<([f32; 2], [f32; 2])> {
+        let cells: Vec<Vec<bool>> =
+            rows.iter().map(|row| row.chars().map(|c| c == '#').collect()).collect();
+        let (width, height) = (cells[0].len(), cells.len());
+
+        outline(width, height, 1.0, |x, y| cells[y][x])
+            .into_iter()
+            .map(|segment| (segment.start, segment.end))
+            .collect()
+    }
+
+    #[test]
+    fn runs_of_nothing_and_a_single_edge() {
+        assert_eq!(runs([false, false].into_iter()), []);
+        assert_eq!(runs([true].into_iter()), [(0, 1)]);
+        assert_eq!(runs([false, true, false].into_iter()), [(1, 2)]);
+    }
+
+    #[test]
+    fn runs_of_a_full_row() {
+        assert_eq!(runs([true; 4].into_iter()), [(0, 4)]);
+    }
+
+    #[test]
+    fn runs_split_at_gaps() {
+        let edges = [true, true, false, true, false, false, true];
+        assert_eq!(runs(edges.into_iter()), [(0, 2), (3, 4), (6, 7)]);
+    }
+
+    #[test]
+    fn single_wall_cell_is_a_square() {
+        assert_eq!(
+            outline_of(&["#"]),
+            [
+                ([0.0, 0.0], [1.0, 0.0]),
+                ([0.0, 1.0], [1.0, 1.0]),
+                ([0.0, 0.0], [0.0, 1.0]),
+                ([1.0, 0.0], [1.0, 1.0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn full_row_of_walls_merges_into_one_segment_per_side() {
+        assert_eq!(
+            outline_of(&["###"]),
+            [
+                ([0.0, 0.0], [3.0, 0.0]),
+                ([0.0, 1.0], [3.0, 1.0]),
+                ([0.0, 0.0], [0.0, 1.0]),
+                ([3.0, 0.0], [3.0, 1.0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn open_cell_splits_horizontal_runs() {
+        let segments = outline_of(&["#.#"]);
+        let top: Vec<_> =
+            segments.iter().filter(|(start, end)| start[1] == 0.0 && end[1] == 0.0).collect();
+
+        assert_eq!(top, [&([0.0, 0.0], [1.0, 0.0]), &([2.0, 0.0], [3.0, 0.0])]);
+        assert_eq!(segments.len(), 8);
+    }
+
+    #[test]
+    fn open_cell_splits_vertical_runs() {
+        let segments = outline_of(&["#", ".", "#"]);
+        let left: Vec<_> =
+            segments.iter().filter(|(start, end)| start[0] == 0.0 && end[0] == 0.0).collect();
+
+        assert_eq!(left, [&([0.0, 0.0], [0.0, 1.0]), &([0.0, 2.0], [0.0, 3.0])]);
+        assert_eq!(segments.len(), 8);
+    }
+}